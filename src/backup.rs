@@ -0,0 +1,275 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, OnDiskCommand, Options, SharedKvStore};
+use crate::crypto::Cipher;
+use crate::error::{KvsError, Result};
+use crate::lock;
+use crate::wal::WalLog;
+
+const MANIFEST_FILE: &str = "backup.json";
+
+/// describes a backup. it's written last, so a backup without it
+/// is incomplete.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    // latest sequence of store when backup started.
+    sequence: u64,
+    cmd_bytes: u64,
+    records: u64,
+    encrypted: bool,
+}
+
+/// cmd.wal of a store at a sequence point. records are only appended
+/// to the file and compaction replaces it instead of changing it, so
+/// its first `cmd_bytes` don't change while they are copied.
+struct BackupPoint {
+    cmd: File,
+    cmd_bytes: u64,
+    sequence: u64,
+    encrypted: bool,
+}
+
+impl KvStore {
+    /// copy the store into an empty directory as of now.
+    /// return the sequence of backup.
+    pub fn backup<P: AsRef<Path>>(&mut self, dest: P) -> Result<u64> {
+        self.backup_point()?.copy_to(dest.as_ref())
+    }
+
+    /// check a backup and restore it into a directory without a store,
+    /// then open the restored store with options. options have to
+    /// carry the encryption key of backup if it's encrypted.
+    pub fn restore<P: AsRef<Path>, Q: AsRef<Path>>(backup: P, dir: Q, options: Options) -> Result<Self> {
+        let (backup, dir) = (backup.as_ref(), dir.as_ref());
+        let manifest = read_manifest(backup)?;
+        let cipher = options.encryption_key.as_ref().map(Cipher::new);
+        if manifest.encrypted != cipher.is_some() {
+            return Err(invalid(if manifest.encrypted {
+                "backup is encrypted, an encryption key is required"
+            } else {
+                "backup isn't encrypted"
+            }));
+        }
+
+        let cmd_path = backup.join("cmd.wal");
+        let cmd = File::open(&cmd_path)?;
+        if cmd.metadata()?.len() != manifest.cmd_bytes {
+            return Err(invalid("size of cmd.wal doesn't match manifest"));
+        }
+        // every record is decoded, so a tampered or truncated one is found.
+        let wal = WalLog::<OnDiskCommand>::with_cipher(cmd, cipher);
+        let mut reader = BufReader::new(&wal.fd);
+        let mut records = 0;
+        let mut iter = wal.iter(&mut reader);
+        for (_, cmd) in &mut iter {
+            if cmd.value.sequence() > manifest.sequence {
+                return Err(invalid("record after sequence of backup"));
+            }
+            records += 1;
+        }
+        iter.finish()?;
+        if records != manifest.records {
+            return Err(invalid("records of cmd.wal don't match manifest"));
+        }
+
+        std::fs::create_dir_all(dir)?;
+        let lock = lock::lock_dir(dir)?;
+        for name in &["cmd.wal", "meta.wal", "hint.wal"] {
+            if dir.join(name).exists() {
+                return Err(KvsError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("{} exists in {}", name, dir.display()))));
+            }
+        }
+        std::fs::copy(&cmd_path, dir.join("cmd.wal"))?;
+        File::open(dir.join("cmd.wal"))?.sync_all()?;
+
+        // the whole cmd.wal is replayed, then a hint keeps the
+        // sequence of backup, which removed keys may be missing.
+        let mut kvs = Self::open_locked(dir, options, lock)?;
+        kvs.latest_seq = manifest.sequence;
+        kvs.compacted_seq = manifest.sequence;
+        kvs.sync()?;
+        kvs.hint_dirty = true;
+        kvs.write_hint()?;
+        Ok(kvs)
+    }
+
+    fn backup_point(&mut self) -> Result<BackupPoint> {
+        self.sync()?;
+        Ok(BackupPoint {
+            // opened again to read with its own position.
+            cmd: File::open(self.path.join("cmd.wal"))?,
+            cmd_bytes: self.wal_cmd.fd.metadata()?.len(),
+            sequence: self.latest_seq,
+            encrypted: self.options.encryption_key.is_some(),
+        })
+    }
+}
+
+impl SharedKvStore {
+    /// copy the store into an empty directory as of now. the store
+    /// is only locked to find the sequence point, writes go on
+    /// while it's copied. return the sequence of backup.
+    pub fn backup<P: AsRef<Path>>(&self, dest: P) -> Result<u64> {
+        let point = self.lock().backup_point()?;
+        point.copy_to(dest.as_ref())
+    }
+}
+
+impl BackupPoint {
+    fn copy_to(self, dest: &Path) -> Result<u64> {
+        std::fs::create_dir_all(dest)?;
+        if dest.join(MANIFEST_FILE).exists() || dest.join("cmd.wal").exists() {
+            return Err(KvsError::IoError(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("a backup exists in {}", dest.display()))));
+        }
+
+        // never overwrite a file, even one created since checked.
+        let fd = OpenOptions::new().write(true).create_new(true).open(dest.join("cmd.wal"))?;
+        let mut writer = BufWriter::new(&fd);
+        let mut reader = BufReader::new(self.cmd.take(self.cmd_bytes));
+        // records are counted by their length prefix, without decoding.
+        let mut records = 0;
+        let mut copied = 0;
+        while copied < self.cmd_bytes {
+            let mut len_bytes = [0u8; 4];
+            reader.read_exact(&mut len_bytes)?;
+            let len = u32::from_be_bytes(len_bytes) as u64;
+            writer.write_all(&len_bytes)?;
+            if std::io::copy(&mut (&mut reader).take(len), &mut writer)? != len {
+                return Err(KvsError::PartialWritten(0, len as usize));
+            }
+            copied += 4 + len;
+            records += 1;
+        }
+        writer.flush()?;
+        std::mem::drop(writer);
+        fd.sync_all()?;
+
+        let manifest = Manifest {
+            sequence: self.sequence,
+            cmd_bytes: self.cmd_bytes,
+            records,
+            encrypted: self.encrypted,
+        };
+        let fd = OpenOptions::new().write(true).create_new(true).open(dest.join(MANIFEST_FILE))?;
+        serde_json::to_writer(&fd, &manifest)?;
+        fd.sync_all()?;
+        File::open(dest)?.sync_all()?;
+        Ok(self.sequence)
+    }
+}
+
+fn read_manifest(backup: &Path) -> Result<Manifest> {
+    match File::open(backup.join(MANIFEST_FILE)) {
+        Ok(fd) => Ok(serde_json::from_reader(fd)?),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            Err(invalid("no manifest, backup is incomplete"))
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn invalid(message: &str) -> KvsError {
+    KvsError::InvalidBackup(message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use super::*;
+    use crate::{EncryptionKey, KvsClient, KvsServer};
+
+    #[test]
+    fn test_backup_and_restore() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let store = SharedKvStore::new(KvStore::open(&tmpdir, Options::default()).unwrap());
+        for i in 0..100 {
+            store.set(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        store.remove(b"key0").unwrap();
+
+        let backup_dir = tempfile::tempdir().unwrap();
+        assert_eq!(store.backup(backup_dir.path()).unwrap(), 101);
+        // a backup is never overwritten.
+        assert!(store.backup(backup_dir.path()).is_err());
+        // not in backup.
+        store.set(b"key1", b"new").unwrap();
+        store.lock().compact().unwrap();
+
+        let restore_dir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::restore(backup_dir.path(), restore_dir.path(), Options::default()).unwrap();
+        assert_eq!(kvs.latest_seq, 101);
+        assert_eq!(kvs.get_bytes(b"key0").unwrap(), None);
+        assert_eq!(kvs.get_bytes(b"key1").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(kvs.keys().len(), 99);
+        std::mem::drop(kvs);
+        assert_eq!(KvStore::open(restore_dir.path(), Options::default()).unwrap().latest_seq, 101);
+        // the restored store isn't overwritten.
+        assert!(KvStore::restore(backup_dir.path(), restore_dir.path(), Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_restore_invalid_backup() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            encryption_key: Some(EncryptionKey::new([7u8; 32])),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        kvs.set_bytes(b"key1", b"value1").unwrap();
+        kvs.set_bytes(b"key2", b"value2").unwrap();
+        let backup_dir = tempfile::tempdir().unwrap();
+        kvs.backup(backup_dir.path()).unwrap();
+
+        let restore_dir = tempfile::tempdir().unwrap();
+        assert!(matches!(KvStore::restore(backup_dir.path(), restore_dir.path(), Options::default()),
+                         Err(KvsError::InvalidBackup(_))));
+
+        // a truncated backup.
+        let cmd = OpenOptions::new().write(true).open(backup_dir.path().join("cmd.wal")).unwrap();
+        let len = cmd.metadata().unwrap().len();
+        cmd.set_len(len - 1).unwrap();
+        assert!(matches!(KvStore::restore(backup_dir.path(), restore_dir.path(), options.clone()),
+                         Err(KvsError::InvalidBackup(_))));
+        std::fs::remove_file(backup_dir.path().join(MANIFEST_FILE)).unwrap();
+        assert!(matches!(KvStore::restore(backup_dir.path(), restore_dir.path(), options),
+                         Err(KvsError::InvalidBackup(_))));
+        assert!(!restore_dir.path().join("cmd.wal").exists());
+    }
+
+    #[test]
+    fn test_backup_by_client() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let store = SharedKvStore::new(KvStore::open(&tmpdir, Options::default()).unwrap());
+        store.set(b"key", b"value").unwrap();
+        let root = tempfile::tempdir().unwrap();
+        let serve = |server: KvsServer| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap();
+            std::thread::spawn(move || server.serve(listener));
+            addr
+        };
+
+        let mut client = KvsClient::connect(serve(KvsServer::new(store.clone()))).unwrap();
+        assert!(client.backup("nightly").is_err());
+        assert!(!root.path().join("nightly").exists());
+
+        let server = KvsServer::new(store).with_backup_root(root.path());
+        let mut client = KvsClient::connect(serve(server)).unwrap();
+        assert_eq!(client.backup("nightly").unwrap(), 1);
+        assert!(root.path().join("nightly").join(MANIFEST_FILE).exists());
+        let outside = tempfile::tempdir().unwrap();
+        assert!(client.backup(outside.path().to_str().unwrap()).is_err());
+        assert!(client.backup("../escaped").is_err());
+        assert!(client.backup("").is_err());
+        assert!(!outside.path().join("cmd.wal").exists());
+        assert!(!root.path().join("..").join("escaped").exists());
+    }
+}
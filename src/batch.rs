@@ -0,0 +1,44 @@
+#[derive(Debug, Clone)]
+pub(crate) enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// a group of writes committed together by `KvStore::write_batch`.
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    /// create an empty batch.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set a key/value pairs in this batch.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Set(key.to_vec(), value.to_vec()));
+        self
+    }
+
+    /// remove a key in this batch.
+    pub fn remove(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.to_vec()));
+        self
+    }
+
+    /// count of writes in this batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// whether there is no write in this batch.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -1,5 +1,13 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::net::TcpListener;
+
 use structopt::StructOpt;
 
+use kvs::{BulkLoader, EncryptionKey, ExportFormat, KvStore, KvsClient, KvsError, KvsServer, NodeId,
+          Options, RaftNode, RaftOptions, Shard, ShardedStore, SharedKvStore};
+
 #[derive(Debug, StructOpt)]
 #[structopt(about = "about kvscli")]
 /// The help message
@@ -9,6 +17,16 @@ enum KvsCliOpt {
         #[structopt()]
         /// The key to get value
         key: String,
+
+        #[structopt(long)]
+        /// Address of a server instead of store in current directory
+        addr: Option<String>,
+
+        #[structopt(long)]
+        /// Addresses of sharded servers, like 127.0.0.1:4001,127.0.0.1:4002
+        shards: Option<String>,
+        #[structopt(flatten)]
+        store: StoreOpt,
     },
     /// Set key/value pairs
     Set {
@@ -19,19 +37,539 @@ enum KvsCliOpt {
         #[structopt()]
         /// The value in k/v pairs
         value: String,
+
+        #[structopt(long)]
+        /// Address of a server instead of store in current directory
+        addr: Option<String>,
+
+        #[structopt(long)]
+        /// Addresses of sharded servers, like 127.0.0.1:4001,127.0.0.1:4002
+        shards: Option<String>,
+        #[structopt(flatten)]
+        store: StoreOpt,
     },
     /// Remove a key from kv Store
     Rm {
         #[structopt()]
         /// The key to remove from kv Store
-        key: String
+        key: String,
+
+        #[structopt(long)]
+        /// Address of a server instead of store in current directory
+        addr: Option<String>,
+
+        #[structopt(long)]
+        /// Addresses of sharded servers, like 127.0.0.1:4001,127.0.0.1:4002
+        shards: Option<String>,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Print changes of keys on a server as they are written
+    Watch {
+        #[structopt(default_value = "")]
+        /// Only keys with this prefix are watched
+        prefix: String,
+
+        #[structopt(long)]
+        /// Print changes in log after this sequence first
+        since: Option<u64>,
+
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        /// Address of the server
+        addr: String,
+    },
+    /// Serve the store in current directory over tcp
+    Serve {
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        /// The address to listen on
+        addr: String,
+
+        #[structopt(long)]
+        /// Address of a leader to replicate, gets are served only
+        follow: Option<String>,
+
+        #[structopt(long)]
+        /// Serve as a member of raft cluster with this id
+        raft_id: Option<NodeId>,
+
+        #[structopt(long, default_value = "")]
+        /// Initial members of a new raft cluster, like 1=127.0.0.1:4001,2=127.0.0.1:4002
+        peers: String,
+
+        #[structopt(long)]
+        /// Serve prometheus metrics over http at /metrics on this address, not for raft members
+        metrics_addr: Option<String>,
+
+        #[structopt(long)]
+        /// Accept backups requested by clients into this directory
+        backup_dir: Option<String>,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Move keys between sharded servers for a new list of shards
+    Rebalance {
+        #[structopt(long)]
+        /// Addresses of current shards
+        from: String,
+
+        #[structopt(long)]
+        /// Addresses of shards after rebalance
+        to: String,
+    },
+    /// Add a member to raft cluster
+    AddNode {
+        #[structopt()]
+        /// Id of the new member
+        id: NodeId,
+
+        #[structopt()]
+        /// Address of the new member
+        node_addr: String,
+
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        /// Address of leader
+        addr: String,
+    },
+    /// Remove a member from raft cluster
+    RemoveNode {
+        #[structopt()]
+        /// Id of the member to remove
+        id: NodeId,
+
+        #[structopt(long, default_value = "127.0.0.1:4000")]
+        /// Address of leader
+        addr: String,
+    },
+    /// Back up the store into an empty directory while it's written
+    Backup {
+        #[structopt()]
+        /// Directory of backup, or its name under backup dir of server if addr is given
+        dest: String,
+
+        #[structopt(long)]
+        /// Address of a server instead of store in current directory
+        addr: Option<String>,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Check a backup and restore it into a directory without a store
+    Restore {
+        #[structopt()]
+        /// Directory of backup
+        backup: String,
+
+        #[structopt(long, default_value = ".")]
+        /// Directory to restore into
+        dir: String,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Write all live pairs of store in current directory to stdout
+    Export {
+        #[structopt(long, default_value = "jsonl")]
+        /// jsonl or csv
+        format: ExportFormat,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Set pairs written by export into store in current directory
+    Import {
+        #[structopt()]
+        /// File to import, stdin if it's not given
+        input: Option<String>,
+
+        #[structopt(long)]
+        /// Load pairs sorted by key into an empty store, bypassing meta.wal
+        bulk: bool,
+
+        #[structopt(long, default_value = "jsonl")]
+        /// jsonl or csv
+        format: ExportFormat,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Check logs of store in current directory, which isn't served
+    Fsck {
+        #[structopt(long)]
+        /// Repair the store if a problem is found
+        repair: bool,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Rebuild index of store in current directory from cmd.wal alone
+    Repair {
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Print records of cmd.wal or meta.wal one per line
+    DumpWal {
+        #[structopt(default_value = "cmd.wal")]
+        /// Path of cmd.wal or meta.wal
+        file: String,
+
+        #[structopt(long)]
+        /// Only records of this key
+        record_key: Option<String>,
+
+        #[structopt(long)]
+        /// Only records with sequence not less than this
+        from_seq: Option<u64>,
+
+        #[structopt(long)]
+        /// Only records with sequence not greater than this
+        to_seq: Option<u64>,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+    /// Print statistics of store in current directory
+    Stats {
+        #[structopt(long)]
+        /// Print as json
+        json: bool,
+        #[structopt(flatten)]
+        store: StoreOpt,
+    },
+}
+
+/// options of a store opened in current directory.
+#[derive(Debug, StructOpt)]
+struct StoreOpt {
+    #[structopt(long = "key", env = "KVS_ENCRYPTION_KEY", hide_env_values = true)]
+    /// Encryption key of logs in 64 hex digits
+    encryption_key: Option<EncryptionKey>,
+
+    #[structopt(long)]
+    /// Compress values of at least this many bytes
+    compress_threshold: Option<usize>,
+
+    #[structopt(long)]
+    /// Keep values shorter than this many bytes in memory
+    inline_threshold: Option<usize>,
+
+    #[structopt(long)]
+    /// Max bytes of values kept in memory
+    inline_budget: Option<usize>,
+
+    #[structopt(long)]
+    /// Write a checkpoint of index every this many writes
+    checkpoint_interval: Option<u64>,
+
+    #[structopt(long, default_value = "0")]
+    /// Max bytes of values cached for reading
+    cache_capacity: usize,
+
+    #[structopt(long)]
+    /// Read cmd.wal through memory map
+    mmap_reads: bool,
+}
+
+impl StoreOpt {
+    fn options(self) -> Options {
+        Options {
+            encryption_key: self.encryption_key,
+            compress_threshold: self.compress_threshold,
+            inline_threshold: self.inline_threshold,
+            inline_budget: self.inline_budget,
+            checkpoint_interval: self.checkpoint_interval,
+            cache_capacity: self.cache_capacity,
+            mmap_reads: self.mmap_reads,
+            ..Options::default()
+        }
+    }
+}
+
+/// a store in current directory, on a server or across shards.
+enum Target {
+    Local(Box<KvStore>),
+    Remote(KvsClient),
+    Sharded(ShardedStore),
+}
+
+impl Target {
+    fn open(addr: Option<String>, shards: Option<String>, options: Options) -> kvs::Result<Self> {
+        match (addr, shards) {
+            (_, Some(shards)) => Ok(Target::Sharded(connect_shards(&shards)?)),
+            (Some(addr), None) => Ok(Target::Remote(KvsClient::connect(addr)?)),
+            (None, None) => Ok(Target::Local(Box::new(KvStore::open(".", options)?))),
+        }
+    }
+
+    fn get(&mut self, key: &str) -> kvs::Result<Option<Vec<u8>>> {
+        match self {
+            Target::Local(store) => store.get_bytes(key.as_bytes()),
+            Target::Remote(client) => client.get(key.as_bytes()),
+            Target::Sharded(sharded) => sharded.get(key.as_bytes()),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> kvs::Result<()> {
+        match self {
+            Target::Local(store) => store.set_bytes(key.as_bytes(), value.as_bytes()),
+            Target::Remote(client) => client.set(key.as_bytes(), value.as_bytes()),
+            Target::Sharded(sharded) => sharded.set(key.as_bytes(), value.as_bytes()),
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> kvs::Result<()> {
+        match self {
+            Target::Local(store) => store.remove_bytes(key.as_bytes()),
+            Target::Remote(client) => client.remove(key.as_bytes()),
+            Target::Sharded(sharded) => sharded.remove(key.as_bytes()),
+        }
+    }
+}
+
+fn connect_shards(addrs: &str) -> kvs::Result<ShardedStore> {
+    let mut shards = Vec::new();
+    for addr in addrs.split(',').filter(|addr| !addr.is_empty()) {
+        shards.push((addr.to_string(), Shard::remote(addr)?));
+    }
+    Ok(ShardedStore::new(shards))
+}
+
+fn rebalance(from: String, to: String) -> kvs::Result<()> {
+    let sharded = connect_shards(&from)?;
+    let current = sharded.shards();
+    let target: Vec<_> = to.split(',').filter(|addr| !addr.is_empty()).collect();
+    let mut moved = 0;
+    for addr in target.iter().filter(|addr| !current.iter().any(|name| name == *addr)) {
+        moved += sharded.add_shard(addr, Shard::remote(addr)?)?;
+    }
+    for name in current.iter().filter(|name| !target.contains(&name.as_str())) {
+        moved += sharded.remove_shard(name)?;
+    }
+    println!("{} keys moved", moved);
+    Ok(())
+}
+
+fn backup(dest: String, addr: Option<String>, options: Options) -> kvs::Result<()> {
+    let seq = match addr {
+        Some(addr) => KvsClient::connect(addr)?.backup(&dest)?,
+        None => KvStore::open(".", options)?.backup(&dest)?,
+    };
+    println!("backup at sequence {}", seq);
+    Ok(())
+}
+
+fn export(format: ExportFormat, options: Options) -> kvs::Result<()> {
+    let mut kvs = KvStore::open(".", options)?;
+    let stdout = std::io::stdout();
+    kvs.export(BufWriter::new(stdout.lock()), format)?;
+    Ok(())
+}
+
+fn import(input: Option<String>, format: ExportFormat, bulk: bool, options: Options) -> kvs::Result<()> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(std::io::stdin().lock()),
+    };
+    let count = if bulk {
+        let mut loader = BulkLoader::new(".", options)?;
+        let count = loader.import(reader, format)?;
+        loader.finish()?.close()?;
+        count
+    } else {
+        let mut kvs = KvStore::open(".", options)?;
+        let count = kvs.import(reader, format)?;
+        kvs.close()?;
+        count
+    };
+    eprintln!("{} pairs imported", count);
+    Ok(())
+}
+
+fn fsck(repair: bool, options: Options) -> kvs::Result<()> {
+    let mut report = KvStore::verify(".", &options)?;
+    println!("cmd.wal: {} records, meta.wal: {} records, latest sequence {}",
+             report.cmd_records, report.meta_records, report.latest_seq);
+    for problem in &report.problems {
+        println!("{}", problem);
+    }
+    if !report.is_ok() && repair {
+        self::repair(options.clone())?;
+        report = KvStore::verify(".", &options)?;
+        for problem in &report.problems {
+            println!("{}", problem);
+        }
+    }
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn repair(options: Options) -> kvs::Result<()> {
+    let kvs = KvStore::repair(".", options)?;
+    println!("repaired with {} keys", kvs.keys().len());
+    kvs.close()
+}
+
+fn stats(json: bool, options: Options) -> kvs::Result<()> {
+    let kvs = KvStore::open(".", options)?;
+    let stats = kvs.stats()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&stats)?);
+    } else {
+        println!("live keys:          {}", stats.live_keys);
+        println!("latest sequence:    {}", stats.latest_seq);
+        println!("cmd.wal bytes:      {} ({} stale)", stats.cmd_bytes, stats.cmd_stale_bytes);
+        println!("meta.wal bytes:     {} ({} stale)", stats.meta_bytes, stats.meta_stale_bytes);
+        println!("index memory bytes: {}", stats.index_bytes);
+        match stats.last_compaction {
+            Some(time) => println!("last compaction:    {} (reclaimed {} bytes)",
+                                   time, stats.last_compaction_reclaimed),
+            None => println!("last compaction:    none since opened"),
+        }
     }
+    kvs.close()
 }
 
+fn dump_wal(file: String, key: Option<String>, from_seq: Option<u64>, to_seq: Option<u64>,
+            options: Options) -> kvs::Result<()> {
+    let key = key.map(String::into_bytes);
+    let problems = KvStore::dump_wal(file, &options, |record| {
+        if key.is_some() && record.key != key {
+            return;
+        }
+        let sequence = record.sequence.unwrap_or(0);
+        if from_seq.is_some_and(|from| sequence < from) || to_seq.is_some_and(|to| sequence > to) {
+            return;
+        }
+        let mut line = format!("{:>10} {:<16}", record.offset, record.kind);
+        if let Some(sequence) = record.sequence {
+            line += &format!(" seq={}", sequence);
+        }
+        if let Some(key) = &record.key {
+            line += &format!(" key={:?}", String::from_utf8_lossy(key));
+        }
+        if let Some(size) = record.value_size {
+            line += &format!(" size={}", size);
+        }
+        if let Some(pointer) = record.pointer {
+            line += &format!(" pointer={}", pointer);
+        }
+        println!("{}", line);
+    })?;
+    for problem in problems {
+        println!("{}", problem);
+    }
+    Ok(())
+}
 
+fn serve_raft(id: NodeId, addr: String, peers: String, options: Options) -> kvs::Result<()> {
+    let mut members = BTreeMap::new();
+    for peer in peers.split(',').filter(|peer| !peer.is_empty()) {
+        let mut parts = peer.splitn(2, '=');
+        match (parts.next().and_then(|id| id.parse().ok()), parts.next()) {
+            (Some(id), Some(addr)) => members.insert(id, addr.to_string()),
+            _ => return Err(KvsError::ServerError(format!("invalid peer {}", peer))),
+        };
+    }
+    let options = RaftOptions { store: options, ..RaftOptions::default() };
+    let _node = RaftNode::start(id, ".", TcpListener::bind(addr)?, members, options)?;
+    loop {
+        std::thread::park();
+    }
+}
+
+fn serve(addr: String, follow: Option<String>, metrics_addr: Option<String>, backup_dir: Option<String>,
+         options: Options) -> kvs::Result<()> {
+    let store = SharedKvStore::new(KvStore::open(".", options)?);
+    let server = match follow {
+        Some(leader) => {
+            let replica = store.clone();
+            std::thread::spawn(move || loop {
+                if let Err(err) = replica.follow(&leader) {
+                    eprintln!("replication from {} stopped: {:?}", leader, err);
+                }
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            });
+            KvsServer::follower(store)
+        },
+        None => KvsServer::new(store),
+    };
+    let server = match backup_dir {
+        Some(root) => server.with_backup_root(root),
+        None => server,
+    };
 
-fn main() -> std::result::Result<(), &'static str> {
-    let arg = KvsCliOpt::from_args();
-    println!("{:?}", arg);
-    Err("unimplemented")
+    if let Some(metrics_addr) = metrics_addr {
+        let listener = TcpListener::bind(&metrics_addr)?;
+        let metrics = server.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = metrics.serve_metrics(listener) {
+                eprintln!("metrics on {} stopped: {:?}", metrics_addr, err);
+            }
+        });
+    }
+    server.run(addr)
+}
+
+fn watch(prefix: String, since: Option<u64>, addr: String) -> kvs::Result<()> {
+    let changes = KvsClient::connect(addr)?.watch(prefix.as_bytes(), since)?;
+    for change in changes {
+        let (seq, key, value) = change?;
+        let key = String::from_utf8_lossy(&key);
+        match value {
+            Some(value) => println!("{} set {} {}", seq, key, String::from_utf8_lossy(&value)),
+            None => println!("{} rm {}", seq, key),
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let result = match KvsCliOpt::from_args() {
+        KvsCliOpt::Get { key, addr, shards, store } => {
+            Target::open(addr, shards, store.options()).and_then(|mut target| {
+            match target.get(&key)? {
+                Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+                None => println!("Key not found"),
+            }
+            Ok(())
+        })},
+        KvsCliOpt::Set { key, value, addr, shards, store } => {
+            Target::open(addr, shards, store.options()).and_then(|mut target| target.set(&key, &value))
+        },
+        KvsCliOpt::Rm { key, addr, shards, store } => {
+            Target::open(addr, shards, store.options()).and_then(|mut target| target.remove(&key))
+        },
+        KvsCliOpt::Rebalance { from, to } => rebalance(from, to),
+        KvsCliOpt::Watch { prefix, since, addr } => watch(prefix, since, addr),
+        KvsCliOpt::Serve { addr, raft_id: Some(id), peers, store, .. } => {
+            serve_raft(id, addr, peers, store.options())
+        },
+        KvsCliOpt::Serve { addr, follow, metrics_addr, backup_dir, store, .. } => {
+            serve(addr, follow, metrics_addr, backup_dir, store.options())
+        },
+        KvsCliOpt::AddNode { id, node_addr, addr } => {
+            KvsClient::connect(addr).and_then(|mut client| client.add_node(id, &node_addr))
+        },
+        KvsCliOpt::RemoveNode { id, addr } => {
+            KvsClient::connect(addr).and_then(|mut client| client.remove_node(id))
+        },
+        KvsCliOpt::Backup { dest, addr, store } => backup(dest, addr, store.options()),
+        KvsCliOpt::Restore { backup, dir, store } => {
+            KvStore::restore(backup, dir, store.options()).and_then(|kvs| kvs.close())
+        },
+        KvsCliOpt::Export { format, store } => export(format, store.options()),
+        KvsCliOpt::Import { input, format, bulk, store } => import(input, format, bulk, store.options()),
+        KvsCliOpt::Fsck { repair, store } => fsck(repair, store.options()),
+        KvsCliOpt::Repair { store } => repair(store.options()),
+        KvsCliOpt::DumpWal { file, record_key, from_seq, to_seq, store } => {
+            dump_wal(file, record_key, from_seq, to_seq, store.options())
+        },
+        KvsCliOpt::Stats { json, store } => stats(json, store.options()),
+    };
+    match result {
+        Ok(()) => {},
+        Err(KvsError::NotFound) => {
+            println!("Key not found");
+            std::process::exit(1);
+        },
+        Err(err) => {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        },
+    }
 }
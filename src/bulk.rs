@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use crate::{Checkpoint, ExportFormat, KvStore, OnDiskCommand, OnDiskMeta, OnDiskPointer, OnDiskValue,
+            Options, Value};
+use crate::compress;
+use crate::crypto::Cipher;
+use crate::error::{KvsError, Result};
+use crate::export::PairReader;
+use crate::hint;
+use crate::lock;
+use crate::wal::WalLog;
+
+// the same files as compaction, so a crash while they are
+// renamed is recovered the same way.
+const CMD_FILE: &str = "cmd.wal.compact";
+const META_FILE: &str = "meta.wal.compact";
+// records appended with one write.
+const BULK_BATCH: usize = 1024;
+
+/// builds a store from pairs sorted by key, much faster than `set`.
+///
+/// values are written into logs already compacted, in large writes
+/// without any flush or sync per key, and the index is written as a
+/// hint. the logs then replace the empty ones like a compaction does,
+/// so a crash leaves either an empty store or a fully loaded one.
+pub struct BulkLoader {
+    dir: PathBuf,
+    options: Options,
+    wal_cmd: WalLog<OnDiskCommand>,
+    wal_meta: WalLog<OnDiskMeta>,
+    pending: Vec<OnDiskCommand>,
+    index: HashMap<Vec<u8>, Value>,
+    last_key: Option<Vec<u8>>,
+    latest_seq: u64,
+    // lock of directory, handed over to the store when it's finished.
+    lock: File,
+}
+
+impl BulkLoader {
+    /// load into a directory without a store. no store may be
+    /// opened on it until the loader is finished.
+    pub fn new<P: AsRef<Path>>(dir: P, options: Options) -> Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let lock = lock::lock_dir(dir)?;
+        for name in &["cmd.wal", "meta.wal"] {
+            if std::fs::metadata(dir.join(name)).is_ok_and(|meta| meta.len() > 0) {
+                return Err(KvsError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::AlreadyExists,
+                    format!("a store exists in {}", dir.display()))));
+            }
+        }
+        let cipher = options.encryption_key.as_ref().map(Cipher::new);
+        // files left by an unfinished load are started over.
+        let open = |name| OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(dir.join(name));
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            options,
+            wal_cmd: WalLog::with_cipher(open(CMD_FILE)?, cipher.clone()),
+            wal_meta: WalLog::with_cipher(open(META_FILE)?, cipher),
+            pending: Vec::with_capacity(BULK_BATCH),
+            index: HashMap::new(),
+            last_key: None,
+            latest_seq: 0,
+            lock,
+        })
+    }
+
+    /// add a pair. keys have to be added in strictly ascending order.
+    pub fn add(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(KvsError::UnsortedKey);
+        }
+        self.last_key = Some(key.to_vec());
+        self.latest_seq += 1;
+        let value = match self.options.compress_threshold {
+            Some(threshold) if value.len() >= threshold => {
+                let data = compress::compress(value)?;
+                if data.len() < value.len() {
+                    OnDiskValue::Compressed(self.latest_seq, data)
+                } else {
+                    OnDiskValue::Content(self.latest_seq, value.to_vec())
+                }
+            },
+            _ => OnDiskValue::Content(self.latest_seq, value.to_vec()),
+        };
+        self.pending.push(OnDiskCommand { key: key.to_vec(), value });
+        if self.pending.len() == BULK_BATCH {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// add pairs written by `KvStore::export`, which are sorted.
+    /// return count of pairs.
+    pub fn import(&mut self, reader: impl BufRead, format: ExportFormat) -> Result<u64> {
+        let mut reader = PairReader::new(reader, format)?;
+        let mut count = 0;
+        while let Some((key, value)) = reader.next_pair()? {
+            match self.add(&key, &value) {
+                Err(KvsError::UnsortedKey) => {
+                    let message = "key isn't greater than previous one".to_string();
+                    return Err(KvsError::InvalidRecord(reader.line(), message));
+                },
+                result => result?,
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// adopt the loaded data into the directory and open the store.
+    pub fn finish(mut self) -> Result<KvStore> {
+        self.write_pending()?;
+        self.wal_cmd.fd.sync_all()?;
+        self.wal_meta.fd.sync_all()?;
+        // cmd.wal is replaced first, as compaction does.
+        std::fs::rename(self.dir.join(CMD_FILE), self.dir.join("cmd.wal"))?;
+        std::fs::rename(self.dir.join(META_FILE), self.dir.join("meta.wal"))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        // without the hint, meta.wal is replayed on open instead.
+        let checkpoint = Checkpoint {
+            latest_seq: self.latest_seq,
+            cmd_offset: self.wal_cmd.fd.metadata()?.len(),
+            meta_offset: self.wal_meta.fd.metadata()?.len(),
+            compacted_seq: self.latest_seq,
+        };
+        let cipher = self.options.encryption_key.as_ref().map(Cipher::new);
+        hint::write_hint(&self.dir, cipher, checkpoint, &self.index)?;
+        KvStore::open_locked(&self.dir, self.options, self.lock)
+    }
+
+    fn write_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let offsets = self.wal_cmd.append_batch(&self.wal_cmd.fd, &self.pending)?;
+        let mut metas = Vec::with_capacity(offsets.len());
+        for (offset, OnDiskCommand{key, value}) in offsets.into_iter().zip(self.pending.drain(..)) {
+            metas.push(OnDiskMeta::CmdIndex(OnDiskCommand {
+                key: key.clone(),
+                value: OnDiskValue::Pointer(value.sequence(), OnDiskPointer{ fid: 0, offset }),
+            }));
+            self.index.insert(key, Value::Location(offset));
+        }
+        self.wal_meta.append_batch(&self.wal_meta.fd, &metas)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bulk_load() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { compress_threshold: Some(64), ..Options::default() };
+        let mut loader = BulkLoader::new(&tmpdir, options.clone()).unwrap();
+        for i in 0..5000 {
+            let value = format!("{:0>100}", i);
+            loader.add(format!("key{:05}", i).as_bytes(), value.as_bytes()).unwrap();
+        }
+        assert!(matches!(loader.add(b"key00001", b"value"), Err(KvsError::UnsortedKey)));
+        let mut kvs = loader.finish().unwrap();
+        assert_eq!(kvs.latest_seq, 5000);
+        assert_eq!(kvs.get_bytes(b"key04999").unwrap(), Some(format!("{:0>100}", 4999).into_bytes()));
+        assert!(!tmpdir.path().join(CMD_FILE).exists());
+
+        kvs.set_bytes(b"key00000", b"new").unwrap();
+        std::mem::drop(kvs);
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        assert_eq!(kvs.keys().len(), 5000);
+        assert_eq!(kvs.latest_seq, 5001);
+        assert_eq!(kvs.get_bytes(b"key00000").unwrap(), Some(b"new".to_vec()));
+        assert!(BulkLoader::new(&tmpdir, options).is_err());
+
+        // meta.wal is replayed without hint.
+        std::mem::drop(kvs);
+        hint::remove_hint(tmpdir.path()).unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        assert_eq!(kvs.keys().len(), 5000);
+        assert_eq!(kvs.get_bytes(b"key00000").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(kvs.get_bytes(b"key02500").unwrap(), Some(format!("{:0>100}", 2500).into_bytes()));
+    }
+
+    #[test]
+    fn test_bulk_import_unsorted() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut loader = BulkLoader::new(&tmpdir, Options::default()).unwrap();
+        let data = "key,value\nb,1\na,2\n";
+        assert!(matches!(loader.import(data.as_bytes(), ExportFormat::Csv),
+                         Err(KvsError::InvalidRecord(3, _))));
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+struct Slot {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    referenced: bool,
+}
+
+/// values read from cmd.wal, bounded by bytes of keys and values.
+/// entries are evicted by CLOCK: the hand skips an entry once if
+/// it's read since last visited.
+pub struct ValueCache {
+    capacity: usize,
+    size: usize,
+    slots: Vec<Option<Slot>>,
+    free: Vec<usize>,
+    index: HashMap<Vec<u8>, usize>,
+    hand: usize,
+}
+
+impl ValueCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            size: 0,
+            slots: Vec::new(),
+            free: Vec::new(),
+            index: HashMap::new(),
+            hand: 0,
+        }
+    }
+
+    /// bytes of cached keys and values.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+        let idx = *self.index.get(key)?;
+        let slot = self.slots[idx].as_mut()?;
+        slot.referenced = true;
+        Some(slot.value.clone())
+    }
+
+    pub fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.remove(&key);
+        let len = key.len() + value.len();
+        if len > self.capacity {
+            return;
+        }
+        while self.size + len > self.capacity {
+            self.evict();
+        }
+
+        let slot = Slot { key: key.clone(), value, referenced: false };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(slot);
+                idx
+            },
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            },
+        };
+        self.index.insert(key, idx);
+        self.size += len;
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        if let Some(idx) = self.index.remove(key) {
+            self.release(idx);
+        }
+    }
+
+    fn release(&mut self, idx: usize) {
+        if let Some(slot) = self.slots[idx].take() {
+            self.size -= slot.key.len() + slot.value.len();
+            self.free.push(idx);
+        }
+    }
+
+    fn evict(&mut self) {
+        loop {
+            if self.hand >= self.slots.len() {
+                self.hand = 0;
+            }
+            let idx = self.hand;
+            self.hand += 1;
+            match self.slots[idx].as_mut() {
+                Some(slot) if slot.referenced => slot.referenced = false,
+                Some(slot) => {
+                    let key = slot.key.clone();
+                    self.index.remove(&key);
+                    self.release(idx);
+                    return;
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_by_bytes() {
+        let mut cache = ValueCache::new(30);
+        cache.insert(b"k1".to_vec(), vec![1u8; 8]);
+        cache.insert(b"k2".to_vec(), vec![2u8; 8]);
+        cache.insert(b"k3".to_vec(), vec![3u8; 8]);
+        assert_eq!(cache.size(), 30);
+
+        // k1 is read, so k2 is evicted first.
+        assert_eq!(cache.get(b"k1"), Some(vec![1u8; 8]));
+        cache.insert(b"k4".to_vec(), vec![4u8; 8]);
+        assert_eq!(cache.get(b"k2"), None);
+        assert_eq!(cache.get(b"k1"), Some(vec![1u8; 8]));
+        assert_eq!(cache.size(), 30);
+
+        // too large to be cached.
+        cache.insert(b"k5".to_vec(), vec![5u8; 40]);
+        assert_eq!(cache.get(b"k5"), None);
+
+        cache.remove(b"k1");
+        assert_eq!(cache.get(b"k1"), None);
+        assert_eq!(cache.size(), 20);
+        cache.insert(b"k1".to_vec(), vec![6u8; 8]);
+        assert_eq!(cache.get(b"k1"), Some(vec![6u8; 8]));
+        assert_eq!(cache.get(b"k3"), Some(vec![3u8; 8]));
+    }
+}
@@ -0,0 +1,106 @@
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpStream, ToSocketAddrs};
+
+use crate::Change;
+use crate::raftlog::NodeId;
+use crate::error::{KvsError, Result};
+use crate::protocol::{self, Request, Response};
+
+/// a client of `KvsServer`.
+pub struct KvsClient {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// connect to a server.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// get a binary value with a given binary key.
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get(key.to_vec()))? {
+            Response::Value(value) => Ok(value),
+            response => Err(KvsError::ServerError(format!("unexpected response {:?}", response))),
+        }
+    }
+
+    /// all live keys of the store.
+    pub fn keys(&mut self) -> Result<Vec<Vec<u8>>> {
+        match self.request(&Request::Keys)? {
+            Response::Keys(keys) => Ok(keys),
+            response => Err(KvsError::ServerError(format!("unexpected response {:?}", response))),
+        }
+    }
+
+    /// set a binary key/value pairs.
+    pub fn set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.request(&Request::Set(key.to_vec(), value.to_vec())).map(|_| ())
+    }
+
+    /// remove a binary key/value pairs by a given binary key.
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.request(&Request::Remove(key.to_vec())).map(|_| ())
+    }
+
+    /// back up the store into a directory on server.
+    /// return the sequence of backup.
+    pub fn backup(&mut self, dest: &str) -> Result<u64> {
+        match self.request(&Request::Backup(dest.to_string()))? {
+            Response::Backup(seq) => Ok(seq),
+            response => Err(KvsError::ServerError(format!("unexpected response {:?}", response))),
+        }
+    }
+
+    /// add a node to a raft cluster through its leader.
+    pub fn add_node(&mut self, id: NodeId, addr: &str) -> Result<()> {
+        self.request(&Request::AddNode(id, addr.to_string())).map(|_| ())
+    }
+
+    /// remove a node from a raft cluster through its leader.
+    pub fn remove_node(&mut self, id: NodeId) -> Result<()> {
+        self.request(&Request::RemoveNode(id)).map(|_| ())
+    }
+
+    /// stream changes of keys with a prefix. changes in log after
+    /// `since` are sent first if it's given.
+    pub fn watch(mut self, prefix: &[u8], since: Option<u64>) -> Result<ChangeStream> {
+        protocol::send(&mut self.writer, &Request::Watch(prefix.to_vec(), since))?;
+        Ok(ChangeStream { reader: self.reader })
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        protocol::send(&mut self.writer, request)?;
+        match protocol::receive(&mut self.reader)? {
+            Response::NotFound => Err(KvsError::NotFound),
+            Response::Error(message) => Err(KvsError::ServerError(message)),
+            Response::NotLeader(leader) => Err(KvsError::NotLeader(leader)),
+            response => Ok(response),
+        }
+    }
+}
+
+/// changes streamed from a server, it ends when the connection fails.
+pub struct ChangeStream {
+    reader: BufReader<TcpStream>,
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match protocol::receive(&mut self.reader) {
+            Ok(Response::Change(change)) => Some(Ok(change)),
+            Ok(Response::Error(message)) => Some(Err(KvsError::ServerError(message))),
+            Ok(response) => Some(Err(KvsError::ServerError(format!("unexpected response {:?}", response)))),
+            Err(KvsError::IoError(ref err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
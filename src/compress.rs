@@ -0,0 +1,58 @@
+use std::io::{Read, Write};
+
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+
+use crate::error::{KvsError, Result};
+
+// bytes of original length before compressed data.
+const LEN_BYTES: usize = 8;
+
+/// deflate data, after its original length.
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder.get_mut().extend_from_slice(&(data.len() as u64).to_be_bytes());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+/// inflate data up to its original length, so a bad stream can't
+/// expand without limit.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < LEN_BYTES {
+        return Err(KvsError::UnexpectedRecord("compressed value without length".into()));
+    }
+    let (len_bytes, data) = data.split_at(LEN_BYTES);
+    let mut len = [0u8; LEN_BYTES];
+    len.copy_from_slice(len_bytes);
+    let len = u64::from_be_bytes(len);
+
+    let mut decoder = DeflateDecoder::new(data);
+    let mut buf = Vec::new();
+    (&mut decoder).take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len || decoder.read(&mut [0u8; 1])? != 0 {
+        return Err(KvsError::UnexpectedRecord("compressed value doesn't match its length".into()));
+    }
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decompress_bounded() {
+        let data = b"value".repeat(100);
+        let compressed = compress(&data).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        // a stream longer or shorter than its length is refused.
+        for len in &[499u64, 501] {
+            let mut tampered = compressed.clone();
+            tampered[..LEN_BYTES].copy_from_slice(&len.to_be_bytes());
+            assert!(matches!(decompress(&tampered), Err(KvsError::UnexpectedRecord(..))));
+        }
+        assert!(matches!(decompress(&[0u8; 4]), Err(KvsError::UnexpectedRecord(..))));
+    }
+}
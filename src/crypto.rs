@@ -0,0 +1,105 @@
+use chacha20poly1305::XChaCha20Poly1305;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+
+use crate::error::{KvsError, Result};
+
+const NONCE_LEN: usize = 24;
+
+/// a 256 bits key to encrypt log records.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// create a key from raw bytes.
+    pub fn new(key: [u8; 32]) -> Self {
+        EncryptionKey(key)
+    }
+}
+
+impl std::str::FromStr for EncryptionKey {
+    type Err = String;
+
+    /// parse a key from 64 hex digits.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.len() != 64 || !s.is_ascii() {
+            return Err("expect a key of 64 hex digits".to_string());
+        }
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)
+                .map_err(|_| "expect a key of 64 hex digits".to_string())?;
+        }
+        Ok(EncryptionKey(key))
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // never leak a key into logs.
+        write!(f, "EncryptionKey(..)")
+    }
+}
+
+/// authenticated encryption of a single record.
+/// output layout is `nonce | ciphertext | tag`. associated data
+/// isn't stored, it has to be given again to open a record.
+#[derive(Clone)]
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self { aead: XChaCha20Poly1305::new((&key.0).into()) }
+    }
+
+    pub fn seal(&self, plain: &[u8], associated: &[u8]) -> Result<Vec<u8>> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let sealed = self.aead.encrypt(&nonce, Payload { msg: plain, aad: associated })
+            .map_err(|_| KvsError::DecryptError)?;
+        let mut data = Vec::with_capacity(NONCE_LEN + sealed.len());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&sealed);
+        Ok(data)
+    }
+
+    pub fn open(&self, data: &[u8], associated: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < NONCE_LEN {
+            return Err(KvsError::DecryptError);
+        }
+        let (nonce, sealed) = data.split_at(NONCE_LEN);
+        self.aead.decrypt(nonce.into(), Payload { msg: sealed, aad: associated })
+            .map_err(|_| KvsError::DecryptError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_and_open() {
+        let cipher = Cipher::new(&EncryptionKey::new([7u8; 32]));
+        let sealed = cipher.seal(b"customer data", b"ad").unwrap();
+        assert!(!sealed.windows(8).any(|w| w == b"customer"));
+        assert_eq!(cipher.open(&sealed, b"ad").unwrap(), b"customer data");
+        assert!(cipher.open(&sealed, b"other ad").is_err());
+
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(cipher.open(&tampered, b"ad").is_err());
+
+        let other = Cipher::new(&EncryptionKey::new([8u8; 32]));
+        assert!(other.open(&sealed, b"ad").is_err());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!("07".repeat(32).parse::<EncryptionKey>().unwrap(), EncryptionKey::new([7u8; 32]));
+        assert_eq!("aB".repeat(32).parse::<EncryptionKey>().unwrap(), EncryptionKey::new([0xab; 32]));
+        assert!("07".repeat(31).parse::<EncryptionKey>().is_err());
+        assert!("zz".repeat(32).parse::<EncryptionKey>().is_err());
+    }
+}
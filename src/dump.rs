@@ -0,0 +1,114 @@
+use std::path::Path;
+
+use crate::{KvStore, OnDiskCommand, OnDiskCompaction, OnDiskMeta, OnDiskPointer, OnDiskValue, Options};
+use crate::crypto::Cipher;
+use crate::error::{KvsError, Result};
+use crate::lock;
+use crate::verify::scan;
+
+/// a decoded record of cmd.wal or meta.wal, for inspection.
+#[derive(Debug, Clone)]
+pub struct WalRecord {
+    /// offset of record in log.
+    pub offset: u64,
+    /// variant of the record, like `Content` or `Pointer`.
+    pub kind: &'static str,
+    /// `None` for compaction markers in meta.wal.
+    pub key: Option<Vec<u8>>,
+    /// `None` for compaction markers in meta.wal.
+    pub sequence: Option<u64>,
+    /// bytes of value as stored, compressed or not.
+    pub value_size: Option<u64>,
+    /// offset in cmd.wal which a pointer of meta.wal points at.
+    pub pointer: Option<u64>,
+}
+
+impl WalRecord {
+    fn from_command(offset: u64, OnDiskCommand{key, value}: OnDiskCommand) -> Self {
+        let sequence = Some(value.sequence());
+        let (kind, value_size, pointer) = match value {
+            OnDiskValue::DeletedKey(..) => ("DeletedKey", None, None),
+            OnDiskValue::Pointer(_, OnDiskPointer{offset, ..}) => ("Pointer", None, Some(offset)),
+            OnDiskValue::Content(_, content) => ("Content", Some(content.len() as u64), None),
+            OnDiskValue::Compressed(_, data) => ("Compressed", Some(data.len() as u64), None),
+        };
+        Self { offset, kind, key: Some(key), sequence, value_size, pointer }
+    }
+
+    fn from_meta(offset: u64, meta: OnDiskMeta) -> Self {
+        match meta {
+            OnDiskMeta::CmdIndex(cmd) => Self::from_command(offset, cmd),
+            OnDiskMeta::Compaction(compaction) => Self {
+                offset,
+                kind: match compaction {
+                    OnDiskCompaction::Start => "CompactionStart",
+                    OnDiskCompaction::Commit => "CompactionCommit",
+                },
+                key: None,
+                sequence: None,
+                value_size: None,
+                pointer: None,
+            },
+        }
+    }
+}
+
+impl KvStore {
+    /// decode cmd.wal or meta.wal of a store record by record, the
+    /// kind of log is told by its file name. records are passed to `f`
+    /// in order. return problems of a broken or partial written tail.
+    pub fn dump_wal<P, F>(path: P, options: &Options, mut f: F) -> Result<Vec<String>>
+    where P: AsRef<Path>, F: FnMut(WalRecord) {
+        let path = path.as_ref();
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new("."));
+        let _lock = lock::lock_dir(dir)?;
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let cipher = options.encryption_key.as_ref().map(Cipher::new);
+        let tail = if name.starts_with("cmd.wal") {
+            scan(path, cipher, |offset, cmd| f(WalRecord::from_command(offset, cmd)))?
+        } else if name.starts_with("meta.wal") {
+            scan(path, cipher, |offset, meta| f(WalRecord::from_meta(offset, meta)))?
+        } else {
+            return Err(KvsError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is neither cmd.wal nor meta.wal", path.display()))));
+        };
+        let mut problems = Vec::new();
+        tail.check_tail(&name, &mut problems);
+        Ok(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dump_wal() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { compress_threshold: Some(16), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        kvs.set_bytes(b"key1", b"value1").unwrap();
+        kvs.set_bytes(b"key2", &[b'v'; 64]).unwrap();
+        kvs.remove_bytes(b"key1").unwrap();
+        kvs.close().unwrap();
+
+        let mut records = Vec::new();
+        let problems = KvStore::dump_wal(tmpdir.path().join("cmd.wal"), &options, |record| {
+            records.push(record);
+        }).unwrap();
+        assert!(problems.is_empty());
+        let kinds: Vec<_> = records.iter().map(|record| record.kind).collect();
+        assert_eq!(kinds, vec!["Content", "Compressed", "DeletedKey"]);
+        assert_eq!(records[0].value_size, Some(6));
+        assert_eq!(records[2].key, Some(b"key1".to_vec()));
+        assert_eq!(records[2].sequence, Some(3));
+
+        let mut pointers = Vec::new();
+        KvStore::dump_wal(tmpdir.path().join("meta.wal"), &options, |record| {
+            pointers.push(record.pointer);
+        }).unwrap();
+        assert_eq!(pointers, vec![Some(records[0].offset), Some(records[1].offset), None]);
+        assert!(KvStore::dump_wal(tmpdir.path().join("hint.wal"), &options, |_| {}).is_err());
+    }
+}
@@ -1,13 +1,57 @@
 use std::convert::From;
+use std::sync::Arc;
 
+/// errors of kvs.
 #[derive(Debug)]
 pub enum KvsError {
+    /// the key to remove doesn't exist.
     NotFound,
+    /// written bytes and expected bytes.
     PartialWritten(usize, usize),
+    /// io error of logs or network.
     IoError(std::io::Error),
+    /// json encoding error.
     SerdeError(serde_json::error::Error),
+    /// bincode encoding error.
+    BincodeError(bincode::Error),
+    /// value isn't a valid utf8 string.
     FromUtf8Error(std::string::FromUtf8Error),
+    /// cmd.wal should only contain values.
     FoundPointerFromDataWal,
+    /// a record is tampered or encrypted with another key.
+    DecryptError,
+    /// a plain record doesn't match its checksum.
+    ChecksumMismatch,
+    /// error reported by a remote server.
+    ServerError(String),
+    /// the node isn't leader of a raft cluster, with address of
+    /// leader if it's known.
+    NotLeader(Option<String>),
+    /// no shard to route a key to.
+    NoShard,
+    /// a backup is incomplete or doesn't match its manifest.
+    InvalidBackup(String),
+    /// a malformed record at a line of imported data.
+    InvalidRecord(u64, String),
+    /// a key for bulk loading isn't greater than the previous one.
+    UnsortedKey,
+    /// repair would drop more of cmd.wal than a broken tail.
+    UnsafeRepair(String),
+    /// an error which failed a group of writes, reported to every
+    /// writer in the group.
+    Shared(Arc<KvsError>),
+    /// a thread panicked while writing the store, so it may be
+    /// inconsistent and isn't written any more.
+    Poisoned,
+    /// the log is compacted while changes are read from it.
+    Compacted,
+    /// a record or value isn't of the kind expected where it's read.
+    UnexpectedRecord(String),
+    /// the directory is locked by another open store or tool.
+    Locked(String),
+    /// a message from network is longer than the limit, with its
+    /// length.
+    MessageTooLarge(u32),
 }
 
 impl From<std::io::Error> for KvsError {
@@ -22,6 +66,12 @@ impl From<serde_json::error::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> Self {
+        KvsError::BincodeError(err)
+    }
+}
+
 impl From<std::string::FromUtf8Error> for KvsError {
     fn from(err: std::string::FromUtf8Error) -> Self {
         KvsError::FromUtf8Error(err)
@@ -29,4 +79,5 @@ impl From<std::string::FromUtf8Error> for KvsError {
 }
 
 
+/// result of kvs operations.
 pub type Result<T> = std::result::Result<T, KvsError>;
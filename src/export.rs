@@ -0,0 +1,272 @@
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, WriteBatch};
+use crate::error::{KvsError, Result};
+
+// writes committed in one batch by import.
+const IMPORT_BATCH: usize = 1024;
+
+/// text format of exported key/value pairs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one json object per line, like `{"key":"k","value":"v"}`.
+    /// bytes which aren't valid utf8 are written as arrays of numbers.
+    Jsonl,
+    /// a `key,value` header, then one record per pair. keys and
+    /// values have to be valid utf8.
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => Err(format!("unknown format {}, expect jsonl or csv", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Bytes {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        match String::from_utf8(bytes) {
+            Ok(text) => Bytes::Text(text),
+            Err(err) => Bytes::Binary(err.into_bytes()),
+        }
+    }
+}
+
+impl From<Bytes> for Vec<u8> {
+    fn from(bytes: Bytes) -> Self {
+        match bytes {
+            Bytes::Text(text) => text.into_bytes(),
+            Bytes::Binary(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: Bytes,
+    value: Bytes,
+}
+
+impl KvStore {
+    /// write all live pairs ordered by key. return count of pairs.
+    pub fn export(&mut self, mut writer: impl Write, format: ExportFormat) -> Result<u64> {
+        let mut keys = self.keys();
+        keys.sort();
+        if format == ExportFormat::Csv {
+            writeln!(writer, "key,value")?;
+        }
+        let mut count = 0;
+        for key in keys {
+            let value = match self.get_bytes(&key)? {
+                Some(value) => value,
+                None => continue,
+            };
+            match format {
+                ExportFormat::Jsonl => {
+                    serde_json::to_writer(&mut writer, &Record { key: key.into(), value: value.into() })?;
+                    writeln!(writer)?;
+                },
+                ExportFormat::Csv => {
+                    let (key, value) = (String::from_utf8(key)?, String::from_utf8(value)?);
+                    writeln!(writer, "{},{}", csv_field(&key), csv_field(&value))?;
+                },
+            }
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// set pairs written by `export`, in batches. a malformed record
+    /// fails the import, batches committed before it are kept.
+    /// return count of pairs.
+    pub fn import(&mut self, reader: impl BufRead, format: ExportFormat) -> Result<u64> {
+        let mut reader = PairReader::new(reader, format)?;
+        let mut batch = WriteBatch::new();
+        let mut count = 0;
+        while let Some((key, value)) = reader.next_pair()? {
+            batch.set(&key, &value);
+            count += 1;
+            if batch.len() == IMPORT_BATCH {
+                self.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+        self.write_batch(batch)?;
+        Ok(count)
+    }
+}
+
+/// reads pairs written by `export`.
+pub(crate) struct PairReader<R> {
+    reader: R,
+    format: ExportFormat,
+    // lines read so far, to locate a malformed record.
+    line: u64,
+}
+
+impl<R: BufRead> PairReader<R> {
+    pub(crate) fn new(reader: R, format: ExportFormat) -> Result<Self> {
+        let mut pairs = Self { reader, format, line: 0 };
+        if format == ExportFormat::Csv {
+            match read_csv_record(&mut pairs.reader, &mut pairs.line)? {
+                Some(header) if header == ["key", "value"] => {},
+                _ => return Err(KvsError::InvalidRecord(1, "expect a key,value header".into())),
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// next pair, `None` at the end.
+    pub(crate) fn next_pair(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        match self.format {
+            ExportFormat::Jsonl => loop {
+                let mut text = String::new();
+                if self.reader.read_line(&mut text)? == 0 {
+                    return Ok(None);
+                }
+                self.line += 1;
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let record: Record = serde_json::from_str(&text)
+                    .map_err(|err| KvsError::InvalidRecord(self.line, err.to_string()))?;
+                return Ok(Some((record.key.into(), record.value.into())));
+            },
+            ExportFormat::Csv => match read_csv_record(&mut self.reader, &mut self.line)? {
+                Some(fields) if fields.len() == 2 => {
+                    let mut fields = fields.into_iter();
+                    Ok(Some((fields.next().unwrap().into_bytes(), fields.next().unwrap().into_bytes())))
+                },
+                Some(fields) => {
+                    let message = format!("expect 2 fields, found {}", fields.len());
+                    Err(KvsError::InvalidRecord(self.line, message))
+                },
+                None => Ok(None),
+            },
+        }
+    }
+
+    /// line of the last pair read.
+    pub(crate) fn line(&self) -> u64 {
+        self.line
+    }
+}
+
+/// quote a field if it contains separators, quotes or line breaks.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// read one record, which spans lines if a quoted field has line
+/// breaks. `line` is advanced by lines read. `None` at the end.
+fn read_csv_record(reader: &mut impl BufRead, line: &mut u64) -> Result<Option<Vec<String>>> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut text = String::new();
+    loop {
+        text.clear();
+        if reader.read_line(&mut text)? == 0 {
+            if quoted {
+                return Err(KvsError::InvalidRecord(*line, "unclosed quote".into()));
+            }
+            return Ok(None);
+        }
+        *line += 1;
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (quoted, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                (true, '"') => quoted = false,
+                (true, c) => field.push(c),
+                (false, '"') if field.is_empty() => quoted = true,
+                (false, ',') => fields.push(std::mem::take(&mut field)),
+                (false, '\r') | (false, '\n') => {},
+                (false, c) => field.push(c),
+            }
+        }
+        if !quoted {
+            fields.push(field);
+            return Ok(Some(fields));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    #[test]
+    fn test_export_and_import() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        kvs.set_bytes(b"plain", b"value").unwrap();
+        kvs.set_bytes(b"quoted", b"a \"b\", c\r\nd").unwrap();
+        kvs.set_bytes(b"removed", b"value").unwrap();
+        kvs.remove_bytes(b"removed").unwrap();
+        for i in 0..2000 {
+            kvs.set_bytes(format!("key{}", i).as_bytes(), b"").unwrap();
+        }
+
+        for format in &[ExportFormat::Jsonl, ExportFormat::Csv] {
+            let mut data = Vec::new();
+            assert_eq!(kvs.export(&mut data, *format).unwrap(), 2002);
+            let dir = tempfile::tempdir().unwrap();
+            let mut imported = KvStore::open(&dir, Options::default()).unwrap();
+            assert_eq!(imported.import(&data[..], *format).unwrap(), 2002);
+            assert_eq!(imported.keys().len(), 2002);
+            assert_eq!(imported.get_bytes(b"quoted").unwrap(), Some(b"a \"b\", c\r\nd".to_vec()));
+            assert_eq!(imported.get_bytes(b"key7").unwrap(), Some(Vec::new()));
+            assert_eq!(imported.get_bytes(b"removed").unwrap(), None);
+            assert_eq!(imported.stats().unwrap().batches, 2);
+        }
+
+        // binary bytes are only exported in jsonl.
+        kvs.set_bytes(b"binary", &[0xff, 0]).unwrap();
+        assert!(kvs.export(Vec::new(), ExportFormat::Csv).is_err());
+        let mut data = Vec::new();
+        kvs.export(&mut data, ExportFormat::Jsonl).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut imported = KvStore::open(&dir, Options::default()).unwrap();
+        imported.import(&data[..], ExportFormat::Jsonl).unwrap();
+        assert_eq!(imported.get_bytes(b"binary").unwrap(), Some(vec![0xff, 0]));
+    }
+
+    #[test]
+    fn test_import_malformed() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        let data = "key,value\na,1\nb,2,3\n";
+        assert!(matches!(kvs.import(data.as_bytes(), ExportFormat::Csv),
+                         Err(KvsError::InvalidRecord(3, _))));
+        assert!(matches!(kvs.import("a,1\n".as_bytes(), ExportFormat::Csv),
+                         Err(KvsError::InvalidRecord(1, _))));
+        let data = "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\"}\n";
+        assert!(matches!(kvs.import(data.as_bytes(), ExportFormat::Jsonl),
+                         Err(KvsError::InvalidRecord(2, _))));
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::{Checkpoint, HintBlock, OnDiskHint, Value};
+use crate::crypto::Cipher;
+use crate::error::Result;
+use crate::wal::WalLog;
+
+const HINT_FILE: &str = "hint.wal";
+const HINT_TMP_FILE: &str = "hint.wal.tmp";
+// keys in one hint record.
+const BLOCK_SIZE: usize = 1024;
+
+/// write index of all live keys. the hint file is replaced atomically,
+/// so a crash leaves either the old one or the new one.
+pub fn write_hint(dir: &Path, cipher: Option<Cipher>, checkpoint: Checkpoint,
+                  index: &HashMap<Vec<u8>, Value>) -> Result<()> {
+    let tmp_path = dir.join(HINT_TMP_FILE);
+    let hint = WalLog::<OnDiskHint>::with_cipher(
+        OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(&tmp_path)?,
+        cipher);
+    let mut writer = BufWriter::new(&hint.fd);
+    hint.append(&mut writer, &OnDiskHint::Checkpoint(checkpoint))?;
+
+    let mut blocks = Vec::with_capacity(BLOCK_SIZE);
+    for (key, value) in index.iter() {
+        if let Value::Deleted = value {
+            continue;
+        }
+        blocks.push(HintBlock{ key: key.clone(), value: value.clone() });
+        if blocks.len() == BLOCK_SIZE {
+            hint.append(&mut writer, &OnDiskHint::Blocks(blocks))?;
+            blocks = Vec::with_capacity(BLOCK_SIZE);
+        }
+    }
+    if !blocks.is_empty() {
+        hint.append(&mut writer, &OnDiskHint::Blocks(blocks))?;
+    }
+    writer.flush()?;
+    std::mem::drop(writer);
+    hint.fd.sync_all()?;
+
+    std::fs::rename(&tmp_path, dir.join(HINT_FILE))?;
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+/// load index from the hint file. a hint is only an accelerator,
+/// so a missing or broken one is simply ignored.
+pub fn read_hint(dir: &Path, cipher: Option<Cipher>) -> Option<(Checkpoint, Vec<HintBlock>)> {
+    let fd = File::open(dir.join(HINT_FILE)).ok()?;
+    let mut reader = BufReader::new(fd.try_clone().ok()?);
+    let hint = WalLog::<OnDiskHint>::with_cipher(fd, cipher);
+
+    let mut iter = hint.iter(&mut reader);
+    let checkpoint = match iter.next() {
+        Some((_, OnDiskHint::Checkpoint(checkpoint))) => checkpoint,
+        _ => return None,
+    };
+    let mut blocks = Vec::new();
+    for (_, record) in &mut iter {
+        match record {
+            OnDiskHint::Blocks(mut records) => blocks.append(&mut records),
+            OnDiskHint::Checkpoint(..) => return None,
+        }
+    }
+    iter.finish().ok()?;
+    Some((checkpoint, blocks))
+}
+
+pub fn remove_hint(dir: &Path) -> Result<()> {
+    match std::fs::remove_file(dir.join(HINT_FILE)) {
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
@@ -7,28 +7,96 @@ use std::fs::OpenOptions;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter, Seek, SeekFrom};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::convert::AsRef;
 use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
 mod error;
-use error::Result;
+pub use error::{KvsError, Result};
 
 mod wal;
 
-#[derive(Serialize, Deserialize, Debug)]
+mod typed;
+pub use typed::{Encoding, TypedStore};
+
+mod compress;
+
+mod cache;
+
+// remap cmd.wal once this many bytes are appended beyond the map.
+const MMAP_SEAL_BYTES: u64 = 16 << 20;
+
+mod hint;
+
+mod crypto;
+pub use crypto::EncryptionKey;
+
+mod options;
+pub use options::{Options, SyncPolicy};
+
+mod stats;
+pub use stats::Stats;
+
+mod batch;
+use batch::BatchOp;
+pub use batch::WriteBatch;
+
+mod shared;
+pub use shared::SharedKvStore;
+
+mod backup;
+
+mod export;
+pub use export::ExportFormat;
+
+mod bulk;
+pub use bulk::BulkLoader;
+
+mod verify;
+pub use verify::VerifyReport;
+
+mod dump;
+pub use dump::WalRecord;
+
+mod metrics;
+
+mod lock;
+
+mod protocol;
+mod server;
+pub use server::KvsServer;
+mod client;
+pub use client::{ChangeStream, KvsClient};
+mod replica;
+
+mod raftlog;
+pub use raftlog::NodeId;
+mod raft;
+pub use raft::{RaftNode, RaftOptions};
+
+mod shard;
+pub use shard::{RemoteShard, Shard, ShardedStore};
+
+mod watch;
+pub use watch::{Change, ChangeCursor, Watcher};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 enum Value {
     Location(u64),
-    Content(String),
+    // kept in memory, along with its location in cmd.wal
+    // so it's able to fall back to a pointer.
+    Content(u64, Vec<u8>),
     Deleted,
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 struct Command {
     sequence: u64,
-    key: String,
+    key: Vec<u8>,
     value: Value,
 }
 
@@ -42,7 +110,29 @@ struct OnDiskPointer {
 enum OnDiskValue {
     DeletedKey(u64),
     Pointer(u64, OnDiskPointer),
-    Content(u64, String),
+    Content(u64, Vec<u8>),
+    Compressed(u64, Vec<u8>),
+}
+
+impl OnDiskValue {
+    fn sequence(&self) -> u64 {
+        match self {
+            OnDiskValue::DeletedKey(sequence)
+                | OnDiskValue::Pointer(sequence, ..)
+                | OnDiskValue::Content(sequence, ..)
+                | OnDiskValue::Compressed(sequence, ..) => *sequence,
+        }
+    }
+
+    /// turn a compressed value back to plain content.
+    fn into_plain(self) -> Result<Self> {
+        match self {
+            OnDiskValue::Compressed(sequence, data) => {
+                Ok(OnDiskValue::Content(sequence, compress::decompress(&data)?))
+            },
+            value => Ok(value),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -57,6 +147,7 @@ enum OnDiskMeta {
     Compaction(OnDiskCompaction)
 }
 
+#[allow(dead_code)]
 #[derive(Serialize, Deserialize, Debug)]
 enum OnDiskIndex {
     ValueIndex { key: String, fid: u8, offset: u32},
@@ -65,135 +156,308 @@ enum OnDiskIndex {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct OnDiskCommand {
-    key: String,
+    key: Vec<u8>,
     value: OnDiskValue,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct HintBlock {
-    key: String,
+    key: Vec<u8>,
+    value: Value,
+}
+
+/// logs covered by a hint file.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Checkpoint {
+    latest_seq: u64,
+    cmd_offset: u64,
+    meta_offset: u64,
+    compacted_seq: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum OnDiskHint {
+    Checkpoint(Checkpoint),
+    Blocks(Vec<HintBlock>),
+}
+
+impl wal::WalEntry for OnDiskCommand {
+    const LOG_ID: u8 = 1;
+}
+
+impl wal::WalEntry for OnDiskMeta {
+    const LOG_ID: u8 = 2;
+}
+
+impl wal::WalEntry for OnDiskHint {
+    const LOG_ID: u8 = 3;
 }
 
 /// core data structure for kvs store
 pub struct KvStore {
+    path: PathBuf,
     wal_cmd: wal::WalLog<OnDiskCommand>,
 
     wal_meta: wal::WalLog<OnDiskMeta>,
     wal_meta_writer: BufWriter<File>,
 
     latest_seq: u64,
-    location_finder: HashMap<String, Value>
+    location_finder: HashMap<Vec<u8>, Value>,
+
+    options: Options,
+    stats: Stats,
+    cache: Option<cache::ValueCache>,
+    // sealed part of cmd.wal, which is never changed until
+    // compaction replaces the file.
+    cmd_map: Option<memmap2::Mmap>,
+
+    writes_since_sync: u64,
+    last_sync: Instant,
+    writes_since_checkpoint: u64,
+    // index changed since hint file written.
+    hint_dirty: bool,
+    // index covers the whole logs, so it's safe to write a hint.
+    index_complete: bool,
+    // compactions since opened, they invalidate offsets in cmd.wal.
+    compactions: u64,
+    // when the last compaction finished and bytes it reclaimed.
+    last_compaction: Option<(SystemTime, u64)>,
+    // durations of compactions since opened.
+    compaction_seconds: metrics::Histogram,
+    // records up to this sequence may be dropped by compaction.
+    compacted_seq: u64,
+    watchers: Vec<watch::WatchSender>,
+    // lock of directory, held while the store is open.
+    _lock: File,
 }
 
 impl KvStore {
     /// create a new object for KvStore within a given directory.
     pub fn new_from<P: AsRef<Path>>(p: P) -> Result<Self> {
-        let meta_fd = OpenOptions::new().read(true).write(true).create(true)
+        let lock = lock::lock_dir(p.as_ref())?;
+        let meta_fd = OpenOptions::new().read(true).write(true).create(true).truncate(false)
             .open(p.as_ref().join("meta.wal"))?;
 
+        let cmd_fd = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(p.as_ref().join("cmd.wal"))?;
+        // existing logs are not loaded.
+        let index_complete = cmd_fd.metadata()?.len() == 0 && meta_fd.metadata()?.len() == 0;
+
         let wal_meta = wal::WalLog::<OnDiskMeta>::new(meta_fd.try_clone()?);
         let wal_meta_writer = BufWriter::new(meta_fd);
         Ok(
             Self {
-                wal_cmd: wal::WalLog::<OnDiskCommand>::new(
-                    OpenOptions::new().read(true).write(true).create(true)
-                        .open(p.as_ref().join("cmd.wal"))?),
+                path: p.as_ref().to_path_buf(),
+                wal_cmd: wal::WalLog::<OnDiskCommand>::new(cmd_fd),
                 wal_meta,
                 wal_meta_writer,
                 latest_seq: 0,
                 location_finder: HashMap::new(),
+                options: Options::default(),
+                stats: Stats::default(),
+                cache: None,
+                cmd_map: None,
+                writes_since_sync: 0,
+                last_sync: Instant::now(),
+                writes_since_checkpoint: 0,
+                hint_dirty: true,
+                index_complete,
+                compactions: 0,
+                last_compaction: None,
+                compaction_seconds: metrics::Histogram::default(),
+                compacted_seq: 0,
+                watchers: Vec::new(),
+                _lock: lock,
             }
         )
     }
 
     /// create a new object for KvStore with wal.log
     pub fn new() -> Result<Self> {
+        let lock = lock::lock_dir(Path::new("."))?;
         let meta_fd = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open("meta.wal")?;
+        let cmd_fd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open("cmd.wal")?;
+        // existing logs are not loaded.
+        let index_complete = cmd_fd.metadata()?.len() == 0 && meta_fd.metadata()?.len() == 0;
         let wal_meta = wal::WalLog::<OnDiskMeta>::new(meta_fd.try_clone()?);
         let wal_meta_writer = BufWriter::new(meta_fd);
         let s = Self {
-            wal_cmd: wal::WalLog::<OnDiskCommand>::new(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open("cmd.wal")?),
+            path: PathBuf::from("."),
+            wal_cmd: wal::WalLog::<OnDiskCommand>::new(cmd_fd),
             wal_meta,
             wal_meta_writer,
             latest_seq: 0,
             location_finder: HashMap::new(),
+            options: Options::default(),
+            stats: Stats::default(),
+            cache: None,
+            cmd_map: None,
+            writes_since_sync: 0,
+            last_sync: Instant::now(),
+            writes_since_checkpoint: 0,
+            hint_dirty: true,
+            index_complete,
+            compactions: 0,
+            last_compaction: None,
+            compaction_seconds: metrics::Histogram::default(),
+            compacted_seq: 0,
+            watchers: Vec::new(),
+            _lock: lock,
         };
         Ok(s)
     }
 
+    /// open a store within a given directory with options.
+    /// log files are created if they don't exist yet. the directory
+    /// is locked until the store is dropped.
+    pub fn open<P: AsRef<Path>>(p: P, options: Options) -> Result<Self> {
+        let lock = lock::lock_dir(p.as_ref())?;
+        Self::open_locked(p, options, lock)
+    }
+
+    /// open a store within a directory whose lock is already taken.
+    pub(crate) fn open_locked<P: AsRef<Path>>(p: P, options: Options, lock: File) -> Result<Self> {
+        for name in &["cmd.wal", "meta.wal"] {
+            OpenOptions::new().write(true).create(true).truncate(false)
+                .open(p.as_ref().join(name))?;
+        }
+        Self::recover(p, options, lock)
+    }
+
     /// recover from a wal log.
     pub fn from_wal<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let lock = lock::lock_dir(p.as_ref())?;
+        Self::recover(p, Options::default(), lock)
+    }
+
+    fn recover<P: AsRef<Path>>(p: P, options: Options, lock: File) -> Result<Self> {
         let wal_cmd_path = p.as_ref().join("cmd.wal");
         let wal_meta_path = p.as_ref().join("meta.wal");
+        Self::finish_compaction(p.as_ref())?;
+        let cipher = options.encryption_key.as_ref().map(crypto::Cipher::new);
 
         let wal_cmd_fd = OpenOptions::new().read(true).write(true).open(&wal_cmd_path)?;
         let wal_meta_fd = OpenOptions::new().read(true).write(true).open(&wal_meta_path)?;
 
         let mut wal_cmd_reader = BufReader::new(wal_cmd_fd.try_clone()?);
         let mut wal_meta_reader = BufReader::new(wal_meta_fd.try_clone()?);
+        let cmd_len = wal_cmd_fd.metadata()?.len();
+        let meta_len = wal_meta_fd.metadata()?.len();
 
-        let wal_cmd = wal::WalLog::<OnDiskCommand>::new(wal_cmd_fd);
-        let wal_meta = wal::WalLog::<OnDiskMeta>::new(wal_meta_fd.try_clone()?);
+        let wal_cmd = wal::WalLog::<OnDiskCommand>::with_cipher(wal_cmd_fd, cipher.clone());
+        let wal_meta = wal::WalLog::<OnDiskMeta>::with_cipher(wal_meta_fd.try_clone()?, cipher.clone());
         let mut wal_meta_writer = BufWriter::new(wal_meta_fd);
 
         let mut location_finder = HashMap::new();
 
         let mut latest_cmd_pos = 0u64;
         let mut latest_seq = 0u64;
-        for (_, meta) in wal::WalLog::<OnDiskMeta>::iter(&mut wal_meta_reader) {
+        let mut meta_start = 0u64;
+        // cmd.wal before this is covered by the checkpoint in hint.
+        let mut cmd_start = 0u64;
+        // the hint covers whole cmd.wal, no need to replay it.
+        let mut hint_covers_cmd = false;
+        // unknown without a hint, so assume the whole log is compacted.
+        let mut compacted_seq = None;
+        if let Some((checkpoint, blocks)) = hint::read_hint(p.as_ref(), cipher) {
+            if checkpoint.cmd_offset <= cmd_len && checkpoint.meta_offset <= meta_len {
+                for HintBlock{key, value} in blocks {
+                    location_finder.insert(key, (checkpoint.latest_seq, value));
+                }
+                latest_seq = checkpoint.latest_seq;
+                meta_start = checkpoint.meta_offset;
+                cmd_start = checkpoint.cmd_offset;
+                hint_covers_cmd = checkpoint.cmd_offset == cmd_len;
+                compacted_seq = Some(checkpoint.compacted_seq);
+            }
+        }
+
+        let mut meta_iter = wal_meta.iter_from(&mut wal_meta_reader, meta_start);
+        for (_, meta) in &mut meta_iter {
             if let OnDiskMeta::CmdIndex(OnDiskCommand{key, value}) = meta {
                     let (seq, pos) = Self::fill_from_meta(&mut location_finder, key, value);
                 latest_seq = std::cmp::max(latest_seq, seq);
                 latest_cmd_pos = std::cmp::max(latest_cmd_pos, pos.unwrap_or(0));
             }
         }
-
-        wal_cmd_reader.seek(SeekFrom::Start(latest_cmd_pos))?;
+        meta_iter.finish()?;
+        // records up to this sequence are already indexed.
+        let indexed_seq = latest_seq;
         wal_meta_writer.seek(SeekFrom::End(0))?;
 
         let mut kvs = Self {
+            path: p.as_ref().to_path_buf(),
             wal_cmd,
             wal_meta,
             wal_meta_writer,
             latest_seq: 0,
             location_finder: HashMap::new(),
+            cache: match options.cache_capacity {
+                0 => None,
+                capacity => Some(cache::ValueCache::new(capacity)),
+            },
+            cmd_map: None,
+            options,
+            stats: Stats::default(),
+            writes_since_sync: 0,
+            last_sync: Instant::now(),
+            writes_since_checkpoint: 0,
+            // not to write a hint if recovery fails.
+            hint_dirty: false,
+            index_complete: true,
+            compactions: 0,
+            last_compaction: None,
+            compaction_seconds: metrics::Histogram::default(),
+            compacted_seq: 0,
+            watchers: Vec::new(),
+            _lock: lock,
         };
 
-        for (offset, OnDiskCommand{key, value}) in
-            wal::WalLog::<OnDiskCommand>::iter(&mut wal_cmd_reader).skip(1) {
-                kvs.fill_from_cmd(&mut location_finder, key, value, offset)?;
+        if !hint_covers_cmd {
+            // the latest indexed record is read again but skipped below.
+            let cmd_start = std::cmp::max(cmd_start, latest_cmd_pos);
+            let mut cmd_iter = kvs.wal_cmd.iter_from(&mut wal_cmd_reader, cmd_start);
+            let replayed: Vec<_> = (&mut cmd_iter)
+                .filter(|(_, cmd)| cmd.value.sequence() > indexed_seq)
+                .collect();
+            cmd_iter.finish()?;
+            for (offset, OnDiskCommand{key, value}) in replayed {
+                    kvs.fill_from_cmd(&mut location_finder, key, value, offset)?;
 
+            }
         }
         latest_seq = std::cmp::max(
-            location_finder.iter().map(|(_k, v)| v.0 ).max().unwrap_or(0),
+            location_finder.values().map(|v| v.0 ).max().unwrap_or(0),
             latest_seq);
 
         let location_finder = location_finder.into_iter().filter(|(_k, v)| {
-            match v.1 {
-                Value::Location(_) => true ,
-                _ => false
-            }
+            !matches!(v.1, Value::Deleted)
         }).map(|(k, v)|{
             (k, v.1)
         }).collect();
 
         kvs.latest_seq = latest_seq;
+        kvs.compacted_seq = compacted_seq.unwrap_or(latest_seq);
         kvs.wal_meta_writer.flush()?;
         kvs.location_finder = location_finder;
+        kvs.enforce_inline_budget();
+        kvs.hint_dirty = !hint_covers_cmd;
+        kvs.seal_cmd_wal()?;
         Ok (kvs)
     }
 
-    fn fill_from_meta(map: &mut std::collections::HashMap<String, (u64, Value)>,
-            key: String, value: OnDiskValue) -> (u64, Option<u64>) {
+    fn fill_from_meta(map: &mut std::collections::HashMap<Vec<u8>, (u64, Value)>,
+            key: Vec<u8>, value: OnDiskValue) -> (u64, Option<u64>) {
 
         match value {
             OnDiskValue::DeletedKey(sequence) => {
@@ -216,22 +480,16 @@ impl KvStore {
                     .or_insert((sequence, Value::Location(offset)));
                 (sequence, Some(offset))
             },
-            OnDiskValue::Content(sequence, value) => {
-                // key may very short. So keep it in memory
-                map.entry(key)
-                    .and_modify(| e: &mut(u64, Value)|{
-                        if e.0 < sequence {
-                            *e = (sequence, Value::Content(value.clone()));
-                        }
-                    })
-                    .or_insert((sequence, Value::Content(value)));
+            OnDiskValue::Content(sequence, ..) | OnDiskValue::Compressed(sequence, ..) => {
+                // values only live in cmd.wal. small ones are inlined
+                // when they are replayed or read from there.
                 (sequence, None)
             }
         }
     }
 
-    fn fill_from_cmd(&mut self, map: &mut std::collections::HashMap<String, (u64, Value)>,
-                     key: String, value: OnDiskValue, offset: u64) -> Result<()> {
+    fn fill_from_cmd(&mut self, map: &mut std::collections::HashMap<Vec<u8>, (u64, Value)>,
+                     key: Vec<u8>, value: OnDiskValue, offset: u64) -> Result<()> {
 
         match value {
             OnDiskValue::DeletedKey(sequence) => {
@@ -245,7 +503,7 @@ impl KvStore {
 
                 let pl = OnDiskMeta::CmdIndex (OnDiskCommand {
                     key,
-                    value: OnDiskValue::DeletedKey(self.latest_seq),
+                    value: OnDiskValue::DeletedKey(sequence),
                 });
                 self.append_meta_wal(&pl)?;
             },
@@ -271,16 +529,22 @@ impl KvStore {
                 );
                 self.append_meta_wal(&pl)?;
             },
-            OnDiskValue::Content(sequence, ..) => {
-                // key in cmd.wal may be long. To save memory,
-                // only keep lcoation
+            OnDiskValue::Content(sequence, ..) | OnDiskValue::Compressed(sequence, ..) => {
+                // value in cmd.wal may be long. To save memory,
+                // only keep lcoation unless it's small enough.
+                let index_value = match value {
+                    OnDiskValue::Content(_, content) if self.inline_allowed(content.len()) => {
+                        Value::Content(offset, content)
+                    },
+                    _ => Value::Location(offset),
+                };
                 map.entry(key.clone())
                     .and_modify(| e: &mut(u64, Value)|{
                         if e.0 < sequence {
-                            *e = (sequence, Value::Location(offset));
+                            *e = (sequence, index_value.clone());
                         }
                     })
-                    .or_insert((sequence, Value::Location(offset)));
+                    .or_insert((sequence, index_value));
                 let pl = OnDiskMeta::CmdIndex(
                     OnDiskCommand {
                         key,
@@ -300,12 +564,45 @@ impl KvStore {
 
     /// get a value with a given key.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some(value) = self.location_finder.get(&key) {
+        match self.get_bytes(key.as_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// set a key/value pairs
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.as_bytes(), value.as_bytes())
+    }
+
+    /// remove a key/value pairs by a given key.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// get a binary value with a given binary key.
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.location_finder.get(key) {
             match value {
                 Value::Location(offset) => {
-                    let od_cmd = self.read_cmd_wal(*offset)?;
+                    let offset = *offset;
+                    if let Some(cache) = self.cache.as_mut() {
+                        if let Some(content) = cache.get(key) {
+                            self.stats.cache_hits += 1;
+                            return Ok(Some(content));
+                        }
+                        self.stats.cache_misses += 1;
+                    }
+                    let od_cmd = self.read_cmd_wal(offset)?;
                     match od_cmd.value {
                         OnDiskValue::Content(_sequence, content) => {
+                            // pointers are replayed from meta.wal without
+                            // values, so small ones are inlined on reading.
+                            if self.inline_allowed(content.len()) {
+                                self.index_insert(key.to_vec(), Value::Content(offset, content.clone()));
+                            } else if let Some(cache) = self.cache.as_mut() {
+                                cache.insert(key.to_vec(), content.clone());
+                            }
                             Ok(Some(content))
                         },
                         OnDiskValue::DeletedKey(_sequence) => {
@@ -314,9 +611,12 @@ impl KvStore {
                         OnDiskValue::Pointer(_sequence, OnDiskPointer{..}) => {
                             Err(error::KvsError::FoundPointerFromDataWal)
                         }
+                        OnDiskValue::Compressed(..) => {
+                            Err(error::KvsError::UnexpectedRecord("a value is still compressed".into()))
+                        }
                     }
                 },
-                Value::Content(content) => {
+                Value::Content(_offset, content) => {
                     Ok(Some(content.clone()))
                 },
                 Value::Deleted => {
@@ -328,79 +628,352 @@ impl KvStore {
         }
     }
 
-    /// set a key/value pairs
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.latest_seq += 1;
-        let cmd = OnDiskCommand {
-            key,
-            value: OnDiskValue::Content(self.latest_seq, value)
-        };
+    /// set a binary key/value pairs
+    pub fn set_bytes(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut results = self.commit_batch(
+            vec![BatchOp::Set(key.to_vec(), value.to_vec())])?;
+        results.pop().unwrap_or(Ok(()))
+    }
 
-        let offset = self.append_cmd_wal(&cmd)?;
+    /// remove a binary key/value pairs by a given binary key.
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        let mut results = self.commit_batch(vec![BatchOp::Remove(key.to_vec())])?;
+        results.pop().unwrap_or(Ok(()))
+    }
+
+    /// apply a batch of writes with one write to each log and
+    /// at most one sync. removing a missing key is ignored.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        for result in self.commit_batch(batch.into_ops())? {
+            match result {
+                Ok(()) | Err(error::KvsError::NotFound) => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
 
-        let pl = OnDiskMeta::CmdIndex(
-            OnDiskCommand {
-                key: cmd.key,
-                value: OnDiskValue::Pointer(
-                    self.latest_seq,
-                    OnDiskPointer{
-                        fid: 0, offset
+    /// write operations in order. the outer error fails the whole
+    /// batch while the inner ones are for each operation.
+    fn commit_batch(&mut self, ops: Vec<BatchOp>) -> Result<Vec<Result<()>>> {
+        let mut results = Vec::with_capacity(ops.len());
+        let mut cmds = Vec::with_capacity(ops.len());
+        // keys alive or not after previous operations in this batch.
+        let mut touched: HashMap<Vec<u8>, bool> = HashMap::new();
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => {
+                    self.latest_seq += 1;
+                    touched.insert(key.clone(), true);
+                    cmds.push(OnDiskCommand {
+                        key,
+                        value: OnDiskValue::Content(self.latest_seq, value),
+                    });
+                    results.push(Ok(()));
+                },
+                BatchOp::Remove(key) => {
+                    let alive = match touched.get(&key) {
+                        Some(alive) => *alive,
+                        None => self.location_finder.contains_key(&key),
+                    };
+                    if alive {
+                        self.latest_seq += 1;
+                        touched.insert(key.clone(), false);
+                        cmds.push(OnDiskCommand {
+                            key,
+                            value: OnDiskValue::DeletedKey(self.latest_seq),
+                        });
+                        results.push(Ok(()));
+                    } else {
+                        results.push(Err(error::KvsError::NotFound));
                     }
-                ),
-            },
-        );
+                },
+            }
+        }
+        if cmds.is_empty() {
+            return Ok(results);
+        }
 
-        self.append_meta_wal(&pl)?;
+        let writes = cmds.len() as u64;
+        // values which may be kept inline, before they are compressed.
+        let inlines: Vec<_> = cmds.iter().map(|cmd| match &cmd.value {
+            OnDiskValue::Content(_, content) if self.inline_allowed(content.len()) => {
+                Some(content.clone())
+            },
+            _ => None,
+        }).collect();
+        let stored = self.append_cmd_wal(cmds)?;
+        let metas: Vec<_> = stored.into_iter().map(|(offset, OnDiskCommand{key, value})| {
+            let value = match value {
+                OnDiskValue::Content(sequence, ..) | OnDiskValue::Compressed(sequence, ..) => {
+                    OnDiskValue::Pointer(sequence, OnDiskPointer{ fid: 0, offset })
+                },
+                value => value,
+            };
+            OnDiskMeta::CmdIndex(OnDiskCommand{ key, value })
+        }).collect();
+        self.wal_meta.append_batch(&mut self.wal_meta_writer, &metas)?;
+        // index entries reach OS together with their data records.
+        self.wal_meta_writer.flush()?;
 
-        let key = match pl {
-            OnDiskMeta::CmdIndex ( OnDiskCommand{key, ..}) => key,
-            _ => panic!("unable to be here"),
-        };
-        self.location_finder.insert(key, Value::Location(offset));
-        Ok(())
+        for (meta, inline) in metas.into_iter().zip(inlines) {
+            match meta {
+                OnDiskMeta::CmdIndex(OnDiskCommand{
+                    key, value: OnDiskValue::Pointer(_, OnDiskPointer{offset, ..})}) => {
+                    // budget may be used up by previous values in batch.
+                    let value = match inline {
+                        Some(content) if self.inline_allowed(content.len()) => {
+                            Value::Content(offset, content)
+                        },
+                        _ => Value::Location(offset),
+                    };
+                    self.index_insert(key, value);
+                },
+                OnDiskMeta::CmdIndex(OnDiskCommand{key, ..}) => {
+                    self.index_remove(&key);
+                },
+                _ => panic!("unable to be here"),
+            }
+        }
+        self.stats.batches += 1;
+        self.finish_writes(writes)?;
+        Ok(results)
     }
 
-    /// remove a key/value pairs by a given key.
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        if self.location_finder.get(&key).is_some() {
-            self.latest_seq += 1;
+    /// sync, remap and checkpoint as configured after records
+    /// are appended and indexed.
+    fn finish_writes(&mut self, writes: u64) -> Result<()> {
+        self.hint_dirty = true;
+        self.maybe_sync(writes)?;
 
-            let cmd = OnDiskCommand {
-                key,
-                value: OnDiskValue::DeletedKey(self.latest_seq),
-            };
-            self.append_cmd_wal(&cmd)?;
-
-            let pl = OnDiskMeta::CmdIndex (OnDiskCommand{
-                key: cmd.key,
-                value: OnDiskValue::DeletedKey(self.latest_seq),
-            });
-            self.append_meta_wal(&pl)?;
-
-            let key = match pl {
-                OnDiskMeta::CmdIndex (OnDiskCommand{
-                    key, ..}) => key,
-                _ => panic!("unable to here"),
-            };
+        if let Some(map) = self.cmd_map.as_ref() {
+            if self.wal_cmd.fd.metadata()?.len() - map.len() as u64 >= MMAP_SEAL_BYTES {
+                self.seal_cmd_wal()?;
+            }
+        }
 
-            self.location_finder.remove(&key);
-            Ok(())
-        } else {
-            Err(error::KvsError::NotFound)
+        self.writes_since_checkpoint += writes;
+        if let Some(interval) = self.options.checkpoint_interval {
+            if self.writes_since_checkpoint >= interval {
+                self.checkpoint()?;
+            }
         }
+        Ok(())
     }
 
-    fn append_cmd_wal(&mut self, cmd: &OnDiskCommand) -> Result<u64> {
+    /// append commands with one write, return them as stored
+    /// along with their offsets.
+    fn append_cmd_wal(&mut self, cmds: Vec<OnDiskCommand>) -> Result<Vec<(u64, OnDiskCommand)>> {
+        let changes = self.changes_to_notify(&cmds);
+        let mut stored = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            stored.push(self.compress_cmd(cmd)?);
+        }
+
         let mut writer = BufWriter::new(&self.wal_cmd.fd);
-        let offset = self.wal_cmd.append(&mut writer, cmd)?;
+        let offsets = self.wal_cmd.append_batch(&mut writer, &stored)?;
         writer.flush()?;
-        Ok(offset)
+        let end = writer.stream_position()?;
+        self.stats.cmd_bytes_written += end - offsets.first().map_or(end, |offset| *offset);
+        std::mem::drop(writer);
+        self.notify(changes);
+        Ok(offsets.into_iter().zip(stored).collect())
+    }
+
+    fn compress_cmd(&mut self, cmd: OnDiskCommand) -> Result<OnDiskCommand> {
+        let OnDiskCommand{key, value} = cmd;
+        let value = match value {
+            OnDiskValue::Content(sequence, content) => {
+                self.stats.values_written += 1;
+                self.stats.value_bytes += content.len() as u64;
+                let data = match self.options.compress_threshold {
+                    Some(threshold) if content.len() >= threshold => {
+                        Some(compress::compress(&content)?)
+                    },
+                    _ => None,
+                };
+                match data {
+                    Some(data) if data.len() < content.len() => {
+                        self.stats.compressed_values += 1;
+                        self.stats.stored_value_bytes += data.len() as u64;
+                        OnDiskValue::Compressed(sequence, data)
+                    },
+                    _ => {
+                        self.stats.stored_value_bytes += content.len() as u64;
+                        OnDiskValue::Content(sequence, content)
+                    },
+                }
+            },
+            value => value,
+        };
+        Ok(OnDiskCommand{ key, value })
     }
 
     // FIXME: get rid of &mut since it's a read operation.
-    fn read_cmd_wal(&self, offset: u64) -> Result<OnDiskCommand> {
-        let mut reader = BufReader::new(&self.wal_cmd.fd);
-        Ok(self.wal_cmd.read(&mut reader, offset)?)
+    fn read_cmd_wal(&mut self, offset: u64) -> Result<OnDiskCommand> {
+        let OnDiskCommand{key, value} = match self.cmd_map.as_ref() {
+            Some(map) if offset < map.len() as u64 => {
+                self.stats.mapped_reads += 1;
+                self.wal_cmd.read_slice(map, offset)?
+            },
+            _ => {
+                let mut reader = BufReader::new(&self.wal_cmd.fd);
+                self.wal_cmd.read(&mut reader, offset)?
+            },
+        };
+        Ok(OnDiskCommand { key, value: value.into_plain()? })
+    }
+
+    /// map cmd.wal written so far for reading. records appended
+    /// later are read from file until it's mapped again.
+    fn seal_cmd_wal(&mut self) -> Result<()> {
+        if !self.options.mmap_reads {
+            return Ok(());
+        }
+        self.cmd_map = None;
+        if self.wal_cmd.fd.metadata()?.len() > 0 {
+            // SAFETY: the map is valid as long as the mapped bytes of
+            // cmd.wal are neither changed nor truncated. this store is
+            // the only writer: the lock of directory keeps other stores
+            // and offline tools out. it only appends after the mapped
+            // range, and compaction replaces the file instead of
+            // changing it.
+            self.cmd_map = Some(unsafe { memmap2::Mmap::map(&self.wal_cmd.fd)? });
+        }
+        Ok(())
+    }
+
+    /// whether a value of this length should be kept inline.
+    fn inline_allowed(&self, len: usize) -> bool {
+        match self.options.inline_threshold {
+            Some(threshold) if len < threshold => {
+                match self.options.inline_budget {
+                    Some(budget) => self.stats.inline_bytes as usize + len <= budget,
+                    None => true,
+                }
+            },
+            _ => false,
+        }
+    }
+
+    fn index_insert(&mut self, key: Vec<u8>, value: Value) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(&key);
+        }
+        if let Value::Content(_, content) = &value {
+            self.stats.inline_values += 1;
+            self.stats.inline_bytes += content.len() as u64;
+        }
+        if let Some(Value::Content(_, old)) = self.location_finder.insert(key, value) {
+            self.stats.inline_values -= 1;
+            self.stats.inline_bytes -= old.len() as u64;
+        }
+    }
+
+    fn index_remove(&mut self, key: &[u8]) {
+        if let Some(cache) = self.cache.as_mut() {
+            cache.remove(key);
+        }
+        if let Some(Value::Content(_, old)) = self.location_finder.remove(key) {
+            self.stats.inline_values -= 1;
+            self.stats.inline_bytes -= old.len() as u64;
+        }
+    }
+
+    /// recount inline values of the whole index, values beyond
+    /// threshold or budget fall back to pointers.
+    fn enforce_inline_budget(&mut self) {
+        let threshold = self.options.inline_threshold.unwrap_or(0);
+        let budget = self.options.inline_budget.unwrap_or(usize::MAX) as u64;
+        let mut inline_values = 0;
+        let mut inline_bytes = 0u64;
+        for value in self.location_finder.values_mut() {
+            if let Value::Content(offset, content) = value {
+                let len = content.len() as u64;
+                if content.len() < threshold && inline_bytes + len <= budget {
+                    inline_values += 1;
+                    inline_bytes += len;
+                } else {
+                    *value = Value::Location(*offset);
+                }
+            }
+        }
+        self.stats.inline_values = inline_values;
+        self.stats.inline_bytes = inline_bytes;
+    }
+
+    /// flush and sync both logs to disk. data records are
+    /// synced before their index entries.
+    pub fn sync(&mut self) -> Result<()> {
+        self.wal_cmd.fd.sync_data()?;
+        self.wal_meta_writer.flush()?;
+        self.wal_meta_writer.get_ref().sync_data()?;
+        self.writes_since_sync = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// flush and sync both logs, then close the store.
+    /// unlike dropping, errors are reported.
+    pub fn close(mut self) -> Result<()> {
+        self.sync()?;
+        self.write_hint()
+    }
+
+    /// write a snapshot of index into hint file and truncate
+    /// meta.wal, so recovery only replays records after it.
+    /// it does nothing if the index isn't loaded from logs.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        if !self.index_complete {
+            return Ok(());
+        }
+        self.sync()?;
+        // without a hint, an empty meta.wal leads to replay whole cmd.wal.
+        hint::remove_hint(&self.path)?;
+        self.wal_meta_writer.get_ref().set_len(0)?;
+        self.wal_meta_writer.get_ref().sync_all()?;
+        self.hint_dirty = true;
+        self.write_hint()?;
+        self.writes_since_checkpoint = 0;
+        self.seal_cmd_wal()
+    }
+
+    /// write index into hint file, so it's loaded directly on next
+    /// start. the logs have to be synced before.
+    fn write_hint(&mut self) -> Result<()> {
+        if !self.hint_dirty || !self.index_complete {
+            return Ok(());
+        }
+        self.wal_meta_writer.flush()?;
+        let checkpoint = Checkpoint {
+            latest_seq: self.latest_seq,
+            cmd_offset: self.wal_cmd.fd.metadata()?.len(),
+            meta_offset: self.wal_meta_writer.get_ref().metadata()?.len(),
+            compacted_seq: self.compacted_seq,
+        };
+        let cipher = self.options.encryption_key.as_ref().map(crypto::Cipher::new);
+        hint::write_hint(&self.path, cipher, checkpoint, &self.location_finder)?;
+        self.hint_dirty = false;
+        Ok(())
+    }
+
+    fn maybe_sync(&mut self, writes: u64) -> Result<()> {
+        self.writes_since_sync += writes;
+        let due = match self.options.sync_policy {
+            SyncPolicy::Always => true,
+            SyncPolicy::EveryWrites(n) => self.writes_since_sync >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            SyncPolicy::Never => false,
+        };
+        if due {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    /// all live keys, in no particular order.
+    pub fn keys(&self) -> Vec<Vec<u8>> {
+        self.location_finder.keys().cloned().collect()
     }
 
     fn append_meta_wal(&mut self, meta: &OnDiskMeta) -> Result<u64> {
@@ -409,20 +982,149 @@ impl KvStore {
         Ok(offset)
     }
 
-    /// compaction reduntant data
+    /// compaction reduntant data. live values are rewritten into
+    /// new logs which then replace the old ones.
+    /// return bytes reclaimed.
     pub fn compact(&mut self) -> Result<u64> {
-        unimplemented!()
+        let key = self.options.encryption_key.clone();
+        self.compact_with_key(key)
+    }
+
+    /// compact into logs encrypted with a given key, which is used
+    /// from then on. the store keeps its key until logs are replaced.
+    fn compact_with_key(&mut self, key: Option<EncryptionKey>) -> Result<u64> {
+        let started = Instant::now();
+        let old_size = self.wal_cmd.fd.metadata()?.len()
+            + self.wal_meta_writer.get_ref().metadata()?.len();
+        let cmd_path = self.path.join("cmd.wal");
+        let meta_path = self.path.join("meta.wal");
+        let cmd_compact_path = self.path.join("cmd.wal.compact");
+        let meta_compact_path = self.path.join("meta.wal.compact");
+        // the hint won't match logs once they are replaced. no hint
+        // is written until the compaction is done.
+        hint::remove_hint(&self.path)?;
+        self.hint_dirty = false;
+
+        let cipher = key.as_ref().map(crypto::Cipher::new);
+        let new_cmd = wal::WalLog::<OnDiskCommand>::with_cipher(
+            OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                .open(&cmd_compact_path)?,
+            cipher.clone());
+        let new_meta = wal::WalLog::<OnDiskMeta>::with_cipher(
+            OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                .open(&meta_compact_path)?,
+            cipher);
+
+        let mut cmd_writer = BufWriter::new(&new_cmd.fd);
+        let mut meta_writer = BufWriter::new(&new_meta.fd);
+        let mut reader = BufReader::new(&self.wal_cmd.fd);
+        let mut location_finder = HashMap::new();
+        for (key, value) in self.location_finder.iter() {
+            let value_offset = match value {
+                Value::Location(offset) | Value::Content(offset, ..) => *offset,
+                Value::Deleted => continue,
+            };
+            // keep compressed values as they are.
+            let disk_value = self.wal_cmd.read(&mut reader, value_offset)?.value;
+            let sequence = match disk_value {
+                OnDiskValue::Content(sequence, ..)
+                    | OnDiskValue::Compressed(sequence, ..) => sequence,
+                _ => continue,
+            };
+            let offset = new_cmd.append(&mut cmd_writer, &OnDiskCommand {
+                key: key.clone(),
+                value: disk_value,
+            })?;
+            new_meta.append(&mut meta_writer, &OnDiskMeta::CmdIndex(OnDiskCommand {
+                key: key.clone(),
+                value: OnDiskValue::Pointer(sequence, OnDiskPointer{ fid: 0, offset }),
+            }))?;
+            let index_value = match value {
+                Value::Content(_, content) => Value::Content(offset, content.clone()),
+                _ => Value::Location(offset),
+            };
+            location_finder.insert(key.clone(), index_value);
+        }
+        cmd_writer.flush()?;
+        meta_writer.flush()?;
+        std::mem::drop(cmd_writer);
+        std::mem::drop(meta_writer);
+        new_cmd.fd.sync_all()?;
+        new_meta.fd.sync_all()?;
+
+        // cmd.wal is replaced first. A leftover meta.wal.compact
+        // without cmd.wal.compact means it's to be renamed.
+        let new_size = new_cmd.fd.metadata()?.len() + new_meta.fd.metadata()?.len();
+        let meta_writer = new_meta.fd.try_clone()?;
+        std::fs::rename(&cmd_compact_path, &cmd_path)?;
+        std::fs::rename(&meta_compact_path, &meta_path)?;
+        // the new logs and key are taken at once, so nothing is
+        // written with a key the logs on disk aren't sealed with.
+        self.options.encryption_key = key;
+        self.wal_meta_writer = BufWriter::new(meta_writer);
+        self.wal_meta = new_meta;
+        self.wal_cmd = new_cmd;
+        self.location_finder = location_finder;
+        File::open(&self.path)?.sync_all()?;
+        self.compactions += 1;
+        self.compacted_seq = self.latest_seq;
+        self.enforce_inline_budget();
+        self.seal_cmd_wal()?;
+        self.hint_dirty = true;
+        self.write_hint()?;
+        let reclaimed = old_size.saturating_sub(new_size);
+        self.last_compaction = Some((SystemTime::now(), reclaimed));
+        self.compaction_seconds.observe(started.elapsed());
+        Ok(reclaimed)
+    }
+
+    /// encrypt logs with a new key by compaction.
+    /// `None` leaves logs in plain text. if compaction fails before
+    /// logs are replaced, the store keeps writing with the old key.
+    pub fn rotate_key(&mut self, key: Option<EncryptionKey>) -> Result<u64> {
+        self.compact_with_key(key)
+    }
+
+    /// finish or roll back a compaction interrupted by crash.
+    fn finish_compaction(p: &Path) -> Result<()> {
+        let cmd_compact_path = p.join("cmd.wal.compact");
+        let meta_compact_path = p.join("meta.wal.compact");
+        if cmd_compact_path.exists() {
+            std::fs::remove_file(&cmd_compact_path)?;
+            if meta_compact_path.exists() {
+                std::fs::remove_file(&meta_compact_path)?;
+            }
+        } else if meta_compact_path.exists() {
+            std::fs::rename(&meta_compact_path, p.join("meta.wal"))?;
+        }
+        Ok(())
     }
 }
 
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // errors can't be reported here, call close() to check them.
+        if self.sync().is_ok() {
+            let _ = self.write_hint();
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    /// as if the process exits without dropping the store, which
+    /// releases only the lock of directory.
+    fn crash(kvs: KvStore) {
+        let kvs = std::mem::ManuallyDrop::new(kvs);
+        kvs._lock.unlock().unwrap();
+    }
+
     #[test]
     fn test_set_two_key() {
-        //let mut kvs = KvStore::new_from(tempfile::tempdir().unwrap()).unwrap();
-        let mut kvs = KvStore::new().unwrap();
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
         kvs.set("key1".into(), "value2".into()).unwrap();
         kvs.set("key2".into(), "value2".into()).unwrap();
     }
@@ -450,4 +1152,495 @@ mod tests {
         kvs = KvStore::from_wal(&tmpdir).unwrap();
         assert_eq!(kvs.get(String::from("key1")).unwrap(), None);
     }
+
+    #[test]
+    fn test_binary_set_and_recover() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        let key = [0u8, 159, 146, 150];
+        let value: Vec<u8> = (0..=255).collect();
+        kvs.set_bytes(&key, &value).unwrap();
+        kvs.set_bytes(b"other", b"\xff\x00").unwrap();
+        kvs.remove_bytes(b"other").unwrap();
+        std::mem::drop(kvs);
+        kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get_bytes(&key).unwrap(), Some(value));
+        assert_eq!(kvs.get_bytes(b"other").unwrap(), None);
+    }
+
+    #[test]
+    fn test_get_non_utf8_value_as_string() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set_bytes(b"key1", &[0xff, 0xfe]).unwrap();
+        match kvs.get("key1".into()) {
+            Err(error::KvsError::FromUtf8Error(_)) => {},
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_compress_large_values() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { compress_threshold: Some(64), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        let large = "{\"name\": \"value\"}".repeat(100);
+        kvs.set("small".into(), "value".into()).unwrap();
+        kvs.set("large".into(), large.clone()).unwrap();
+
+        let stats = kvs.stats().unwrap();
+        assert_eq!(stats.values_written, 2);
+        assert_eq!(stats.compressed_values, 1);
+        assert!(stats.compression_ratio() < 0.5);
+        assert_eq!(kvs.get("large".into()).unwrap(), Some(large.clone()));
+        std::mem::drop(kvs);
+
+        let cmd_len = std::fs::metadata(tmpdir.path().join("cmd.wal")).unwrap().len();
+        assert!(cmd_len < large.len() as u64);
+
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        assert_eq!(kvs.get("large".into()).unwrap(), Some(large));
+        assert_eq!(kvs.get("small".into()).unwrap(), Some("value".into()));
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        let meta_len = |dir: &tempfile::TempDir| {
+            std::fs::metadata(dir.path().join("meta.wal")).unwrap().len()
+        };
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        assert!(meta_len(&tmpdir) > 0);
+        kvs.sync().unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { sync_policy: SyncPolicy::Always, ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        assert!(meta_len(&tmpdir) > 0);
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { sync_policy: SyncPolicy::EveryWrites(3), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+        assert_eq!(kvs.writes_since_sync, 2);
+        kvs.remove("key1".into()).unwrap();
+        assert_eq!(kvs.writes_since_sync, 0);
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            sync_policy: SyncPolicy::Interval(std::time::Duration::from_millis(0)),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        assert!(meta_len(&tmpdir) > 0);
+    }
+
+    #[test]
+    fn test_index_flushed_with_data() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+        crash(kvs);
+
+        let meta = wal::WalLog::<OnDiskMeta>::new(File::open(tmpdir.path().join("meta.wal")).unwrap());
+        let mut reader = BufReader::new(File::open(tmpdir.path().join("meta.wal")).unwrap());
+        assert_eq!(meta.iter(&mut reader).count(), 2);
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+    }
+
+    #[test]
+    fn test_close() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.close().unwrap();
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+    }
+
+    #[test]
+    fn test_load_from_hint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        for i in 0..100 {
+            kvs.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        kvs.remove("key0".into()).unwrap();
+        kvs.close().unwrap();
+        assert!(tmpdir.path().join("hint.wal").exists());
+
+        let meta_len = std::fs::metadata(tmpdir.path().join("meta.wal")).unwrap().len();
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert!(!kvs.hint_dirty);
+        assert_eq!(kvs.get("key0".into()).unwrap(), None);
+        assert_eq!(kvs.get("key99".into()).unwrap(), Some("value99".into()));
+        assert_eq!(kvs.latest_seq, 101);
+        std::mem::drop(kvs);
+        assert_eq!(std::fs::metadata(tmpdir.path().join("meta.wal")).unwrap().len(), meta_len);
+
+        // writes after the hint are recovered from logs.
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        kvs.set("key1".into(), "new".into()).unwrap();
+        kvs.remove("key2".into()).unwrap();
+        crash(kvs);
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), None);
+        assert_eq!(kvs.get("key3".into()).unwrap(), Some("value3".into()));
+        assert_eq!(kvs.latest_seq, 103);
+    }
+
+    #[test]
+    fn test_no_hint_from_partial_index() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        crash(kvs);
+
+        // logs exist but are not loaded by new_from.
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+        kvs.close().unwrap();
+        assert!(!tmpdir.path().join("hint.wal").exists());
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { checkpoint_interval: Some(10), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        for i in 0..20 {
+            kvs.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        kvs.remove("key0".into()).unwrap();
+        for i in 20..25 {
+            kvs.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        crash(kvs);
+
+        // only records after last checkpoint are left in meta.wal.
+        let meta = wal::WalLog::<OnDiskMeta>::new(File::open(tmpdir.path().join("meta.wal")).unwrap());
+        let mut reader = BufReader::new(File::open(tmpdir.path().join("meta.wal")).unwrap());
+        assert_eq!(meta.iter(&mut reader).count(), 6);
+
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        assert_eq!(kvs.get("key0".into()).unwrap(), None);
+        for i in 1..25 {
+            assert_eq!(kvs.get(format!("key{}", i)).unwrap(), Some(format!("value{}", i)));
+        }
+        assert_eq!(kvs.latest_seq, 26);
+    }
+
+    #[test]
+    fn test_recover_idempotent() {
+        let meta_path = |dir: &tempfile::TempDir| dir.path().join("meta.wal");
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        for i in 0..10 {
+            kvs.set(format!("key{}", i), format!("value{}", i)).unwrap();
+        }
+        kvs.remove("key0".into()).unwrap();
+        kvs.remove("key9".into()).unwrap();
+        crash(kvs);
+        let meta_len = std::fs::metadata(meta_path(&tmpdir)).unwrap().len();
+
+        // index entries of the last records are lost in crash.
+        let meta = wal::WalLog::<OnDiskMeta>::new(File::open(meta_path(&tmpdir)).unwrap());
+        let mut reader = BufReader::new(File::open(meta_path(&tmpdir)).unwrap());
+        let offsets: Vec<_> = meta.iter(&mut reader).map(|(offset, _)| offset).collect();
+        OpenOptions::new().write(true).open(meta_path(&tmpdir)).unwrap()
+            .set_len(offsets[offsets.len() - 3]).unwrap();
+
+        for _ in 0..5 {
+            let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+            assert_eq!(kvs.latest_seq, 12);
+            assert_eq!(kvs.get("key0".into()).unwrap(), None);
+            assert_eq!(kvs.get("key8".into()).unwrap(), Some("value8".into()));
+            assert_eq!(kvs.get("key9".into()).unwrap(), None);
+            // no hint, so every reopen replays logs.
+            crash(kvs);
+            assert_eq!(std::fs::metadata(meta_path(&tmpdir)).unwrap().len(), meta_len);
+        }
+    }
+
+    #[test]
+    fn test_inline_small_values() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            inline_threshold: Some(16),
+            inline_budget: Some(40),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        for i in 0..5 {
+            kvs.set(format!("key{}", i), format!("value{:05}", i)).unwrap();
+        }
+        kvs.set("large".into(), "a large value beyond threshold".into()).unwrap();
+        assert_eq!(kvs.stats().unwrap().inline_values, 4);
+        assert_eq!(kvs.stats().unwrap().inline_bytes, 40);
+        assert!(matches!(kvs.location_finder.get(&b"key4"[..]), Some(Value::Location(_))));
+        assert!(matches!(kvs.location_finder.get(&b"large"[..]), Some(Value::Location(_))));
+
+        kvs.remove("key0".into()).unwrap();
+        assert_eq!(kvs.stats().unwrap().inline_bytes, 30);
+        kvs.set("key1".into(), "new".into()).unwrap();
+        assert_eq!(kvs.stats().unwrap().inline_values, 3);
+        assert_eq!(kvs.stats().unwrap().inline_bytes, 23);
+        // read from cmd.wal, then kept inline.
+        assert_eq!(kvs.get("key4".into()).unwrap(), Some("value00004".into()));
+        assert_eq!(kvs.stats().unwrap().inline_values, 4);
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        kvs.compact().unwrap();
+        assert_eq!(kvs.stats().unwrap().inline_values, 4);
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value00002".into()));
+        kvs.close().unwrap();
+
+        // inline values are loaded from hint within a smaller budget.
+        let options = Options { inline_budget: Some(20), ..options };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        assert!(kvs.stats().unwrap().inline_bytes <= 20);
+        assert_eq!(kvs.stats().unwrap().inline_values, 2);
+        for i in 2..5 {
+            assert_eq!(kvs.get(format!("key{}", i)).unwrap(), Some(format!("value{:05}", i)));
+        }
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        assert_eq!(kvs.get("large".into()).unwrap(), Some("a large value beyond threshold".into()));
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { cache_capacity: 64, ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+        let stats = kvs.stats().unwrap();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (1, 2));
+        assert_eq!(stats.cache_bytes, 20);
+
+        // invalidated by writes.
+        kvs.set("key1".into(), "new".into()).unwrap();
+        kvs.remove("key2".into()).unwrap();
+        assert_eq!(kvs.stats().unwrap().cache_bytes, 0);
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), None);
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        let stats = kvs.stats().unwrap();
+        assert_eq!((stats.cache_hits, stats.cache_misses), (2, 3));
+
+        // cached values are still valid after compaction.
+        kvs.compact().unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        assert_eq!(kvs.stats().unwrap().cache_hits, 3);
+    }
+
+    #[test]
+    fn test_mmap_reads() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            mmap_reads: true,
+            compress_threshold: Some(32),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("large".into(), "x".repeat(100)).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.stats().unwrap().mapped_reads, 0);
+
+        kvs.checkpoint().unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.get("large".into()).unwrap(), Some("x".repeat(100)));
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+        assert_eq!(kvs.stats().unwrap().mapped_reads, 2);
+
+        kvs.set("key1".into(), "new".into()).unwrap();
+        kvs.compact().unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("new".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+        assert_eq!(kvs.stats().unwrap().mapped_reads, 4);
+        std::mem::drop(kvs);
+
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        assert_eq!(kvs.get("large".into()).unwrap(), Some("x".repeat(100)));
+        assert_eq!(kvs.stats().unwrap().mapped_reads, 1);
+    }
+
+    #[test]
+    fn test_ignore_broken_hint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+        kvs.close().unwrap();
+        std::fs::write(tmpdir.path().join("hint.wal"), b"broken").unwrap();
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key1".into()).unwrap(), Some("value1".into()));
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set("key0".into(), "value0".into()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.set(b"key1", b"value1")
+            .set(b"key2", b"value2")
+            .remove(b"key1")
+            .remove(b"key0")
+            .remove(b"missing");
+        assert_eq!(batch.len(), 5);
+        kvs.write_batch(batch).unwrap();
+        assert_eq!(kvs.stats().unwrap().batches, 2);
+        std::mem::drop(kvs);
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key0".into()).unwrap(), None);
+        assert_eq!(kvs.get("key1".into()).unwrap(), None);
+        assert_eq!(kvs.get("key2".into()).unwrap(), Some("value2".into()));
+    }
+
+    #[test]
+    fn test_compact() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        for i in 0..100 {
+            kvs.set(format!("key{}", i % 10), format!("value{}", i)).unwrap();
+        }
+        kvs.remove("key0".into()).unwrap();
+        assert!(kvs.compact().unwrap() > 0);
+        kvs.set("key10".into(), "value10".into()).unwrap();
+        assert_eq!(kvs.get("key9".into()).unwrap(), Some("value99".into()));
+        std::mem::drop(kvs);
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get("key0".into()).unwrap(), None);
+        for i in 1..10 {
+            assert_eq!(kvs.get(format!("key{}", i)).unwrap(), Some(format!("value{}", 90 + i)));
+        }
+        assert_eq!(kvs.get("key10".into()).unwrap(), Some("value10".into()));
+    }
+
+    #[test]
+    fn test_encrypt_and_rotate_key() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let key1 = EncryptionKey::new([1u8; 32]);
+        let key2 = EncryptionKey::new([2u8; 32]);
+        let options = Options { encryption_key: Some(key1.clone()), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set("secret".into(), "customer-data".into()).unwrap();
+        std::mem::drop(kvs);
+
+        for name in &["cmd.wal", "meta.wal"] {
+            let data = std::fs::read(tmpdir.path().join(name)).unwrap();
+            assert!(!data.windows(6).any(|w| w == b"secret"));
+        }
+
+        let wrong = Options { encryption_key: Some(key2.clone()), ..Options::default() };
+        assert!(KvStore::open(&tmpdir, wrong.clone()).is_err());
+
+        let options = Options { encryption_key: Some(key1), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        assert_eq!(kvs.get("secret".into()).unwrap(), Some("customer-data".into()));
+
+        // a failed rotation keeps the old key.
+        std::fs::create_dir(tmpdir.path().join("cmd.wal.compact")).unwrap();
+        assert!(kvs.rotate_key(Some(key2.clone())).is_err());
+        std::fs::remove_dir(tmpdir.path().join("cmd.wal.compact")).unwrap();
+        kvs.set("after".into(), "failed rotation".into()).unwrap();
+        std::mem::drop(kvs);
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        assert_eq!(kvs.get("after".into()).unwrap(), Some("failed rotation".into()));
+
+        kvs.rotate_key(Some(key2)).unwrap();
+        assert_eq!(kvs.get("secret".into()).unwrap(), Some("customer-data".into()));
+        std::mem::drop(kvs);
+
+        assert!(KvStore::open(&tmpdir, options).is_err());
+        let mut kvs = KvStore::open(&tmpdir, wrong).unwrap();
+        assert_eq!(kvs.get("secret".into()).unwrap(), Some("customer-data".into()));
+    }
+
+    #[test]
+    fn test_detect_tampered_record() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            encryption_key: Some(EncryptionKey::new([1u8; 32])),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        std::mem::drop(kvs);
+
+        // replay logs without hint, then load from hint.
+        for (name, with_hint) in &[("meta.wal", false), ("cmd.wal", false), ("cmd.wal", true)] {
+            if *with_hint {
+                KvStore::open(&tmpdir, options.clone()).unwrap().close().unwrap();
+            } else {
+                std::fs::remove_file(tmpdir.path().join("hint.wal")).ok();
+            }
+            let path = tmpdir.path().join(name);
+            let mut data = std::fs::read(&path).unwrap();
+            data[10] ^= 0xff;
+            std::fs::write(&path, &data).unwrap();
+            let result = KvStore::open(&tmpdir, options.clone())
+                .and_then(|mut kvs| kvs.get("key1".into()));
+            match result {
+                Err(error::KvsError::DecryptError) => {},
+                other => panic!("unexpected result {:?}", other),
+            }
+            data[10] ^= 0xff;
+            std::fs::write(&path, &data).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_detect_moved_record() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            encryption_key: Some(EncryptionKey::new([1u8; 32])),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("key2".into(), "value2".into()).unwrap();
+        kvs.close().unwrap();
+
+        // records of the same size swapped in cmd.wal.
+        let path = tmpdir.path().join("cmd.wal");
+        let data = std::fs::read(&path).unwrap();
+        let (first, second) = data.split_at(data.len() / 2);
+        std::fs::write(&path, [second, first].concat()).unwrap();
+        let report = KvStore::verify(&tmpdir, &options).unwrap();
+        assert!(report.problems[0].starts_with("cmd.wal: broken record at offset 0"), "{:?}", report.problems);
+
+        // a record of meta.wal spliced into cmd.wal.
+        let meta = std::fs::read(tmpdir.path().join("meta.wal")).unwrap();
+        std::fs::write(&path, &meta).unwrap();
+        let report = KvStore::verify(&tmpdir, &options).unwrap();
+        assert!(report.problems[0].starts_with("cmd.wal: broken record at offset 0"), "{:?}", report.problems);
+    }
 }
@@ -0,0 +1,38 @@
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::Path;
+
+use crate::error::{KvsError, Result};
+
+const LOCK_FILE: &str = "LOCK";
+
+/// take the exclusive lock of a store directory, which is held by an
+/// open store or an offline tool working on its logs. it's released
+/// when the returned file is closed.
+pub(crate) fn lock_dir(dir: &Path) -> Result<File> {
+    let fd = OpenOptions::new().write(true).create(true).truncate(false)
+        .open(dir.join(LOCK_FILE))?;
+    match fd.try_lock() {
+        Ok(()) => Ok(fd),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked(dir.display().to_string())),
+        Err(TryLockError::Error(err)) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KvStore, Options};
+
+    #[test]
+    fn test_store_locks_dir() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        assert!(matches!(KvStore::open(&tmpdir, Options::default()), Err(KvsError::Locked(..))));
+        assert!(matches!(KvStore::verify(&tmpdir, &Options::default()), Err(KvsError::Locked(..))));
+        assert!(matches!(KvStore::repair(&tmpdir, Options::default()), Err(KvsError::Locked(..))));
+
+        std::mem::drop(kvs);
+        KvStore::verify(&tmpdir, &Options::default()).unwrap();
+        KvStore::open(&tmpdir, Options::default()).unwrap();
+    }
+}
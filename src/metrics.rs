@@ -0,0 +1,250 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::SharedKvStore;
+use crate::error::{KvsError, Result};
+use crate::protocol::Request;
+
+// upper bounds of histogram buckets in seconds.
+const BUCKETS: [f64; 14] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01,
+    0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 10.0,
+];
+// requests whose latency is observed.
+const TIMED_OPS: [&str; 3] = ["get", "set", "remove"];
+
+/// counts of observed durations by bucket.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Histogram {
+    // observations in each bucket, not cumulative.
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub(crate) fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    /// write buckets, sum and count with labels like `op="get"`.
+    fn render(&self, out: &mut impl Write, name: &str, labels: &str) -> Result<()> {
+        let bucket_name = format!("{}_bucket", name);
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(&self.counts) {
+            cumulative += count;
+            let labels = format!("{}{}le=\"{}\"", labels, separator, bound);
+            writeln!(out, "{} {}", series(&bucket_name, &labels), cumulative)?;
+        }
+        let labels_inf = format!("{}{}le=\"+Inf\"", labels, separator);
+        writeln!(out, "{} {}", series(&bucket_name, &labels_inf), self.count)?;
+        writeln!(out, "{} {}", series(&format!("{}_sum", name), labels), self.sum)?;
+        writeln!(out, "{} {}", series(&format!("{}_count", name), labels), self.count)?;
+        Ok(())
+    }
+}
+
+/// counters and latencies of requests served by a server.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    inner: Mutex<Registry>,
+}
+
+#[derive(Default)]
+struct Registry {
+    requests: BTreeMap<&'static str, u64>,
+    latencies: BTreeMap<&'static str, Histogram>,
+    errors: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    /// record a request with its result.
+    pub(crate) fn observe<T>(&self, op: &'static str, elapsed: Duration, result: &Result<T>) {
+        let mut registry = self.inner.lock().unwrap();
+        *registry.requests.entry(op).or_default() += 1;
+        if TIMED_OPS.contains(&op) {
+            registry.latencies.entry(op).or_default().observe(elapsed);
+        }
+        if let Err(err) = result {
+            *registry.errors.entry(error_kind(err)).or_default() += 1;
+        }
+    }
+
+    /// metrics of requests and of the served store in prometheus
+    /// text format.
+    pub(crate) fn render(&self, store: &SharedKvStore) -> Result<Vec<u8>> {
+        let (cmd_bytes_written, compaction_seconds) = {
+            let store = store.lock();
+            (store.stats.cmd_bytes_written, store.compaction_seconds.clone())
+        };
+        let registry = self.inner.lock().unwrap();
+        let mut out = Vec::new();
+
+        writeln!(out, "# HELP kvs_requests_total Requests served by operation.")?;
+        writeln!(out, "# TYPE kvs_requests_total counter")?;
+        for (op, count) in &registry.requests {
+            writeln!(out, "kvs_requests_total{{op=\"{}\"}} {}", op, count)?;
+        }
+
+        writeln!(out, "# HELP kvs_request_duration_seconds Latency of requests by operation.")?;
+        writeln!(out, "# TYPE kvs_request_duration_seconds histogram")?;
+        for op in &TIMED_OPS {
+            let empty = Histogram::default();
+            let histogram = registry.latencies.get(op).unwrap_or(&empty);
+            histogram.render(&mut out, "kvs_request_duration_seconds", &format!("op=\"{}\"", op))?;
+        }
+
+        writeln!(out, "# HELP kvs_errors_total Failed requests by error.")?;
+        writeln!(out, "# TYPE kvs_errors_total counter")?;
+        for (kind, count) in &registry.errors {
+            writeln!(out, "kvs_errors_total{{kind=\"{}\"}} {}", kind, count)?;
+        }
+
+        writeln!(out, "# HELP kvs_cmd_wal_written_bytes_total Bytes appended to cmd.wal by writes.")?;
+        writeln!(out, "# TYPE kvs_cmd_wal_written_bytes_total counter")?;
+        writeln!(out, "kvs_cmd_wal_written_bytes_total {}", cmd_bytes_written)?;
+
+        writeln!(out, "# HELP kvs_compaction_duration_seconds Duration of compactions.")?;
+        writeln!(out, "# TYPE kvs_compaction_duration_seconds histogram")?;
+        compaction_seconds.render(&mut out, "kvs_compaction_duration_seconds", "")?;
+        Ok(out)
+    }
+}
+
+/// name of a request in metrics.
+pub(crate) fn op_name(request: &Request) -> &'static str {
+    match request {
+        Request::Get(..) => "get",
+        Request::Set(..) => "set",
+        Request::Remove(..) => "remove",
+        Request::Keys => "keys",
+        Request::Replicate(..) => "replicate",
+        Request::Watch(..) => "watch",
+        Request::Raft(..) => "raft",
+        Request::AddNode(..) => "add_node",
+        Request::RemoveNode(..) => "remove_node",
+        Request::Backup(..) => "backup",
+    }
+}
+
+fn error_kind(err: &KvsError) -> &'static str {
+    match err {
+        KvsError::NotFound => "NotFound",
+        KvsError::PartialWritten(..) => "PartialWritten",
+        KvsError::IoError(..) => "IoError",
+        KvsError::SerdeError(..) => "SerdeError",
+        KvsError::BincodeError(..) => "BincodeError",
+        KvsError::FromUtf8Error(..) => "FromUtf8Error",
+        KvsError::FoundPointerFromDataWal => "FoundPointerFromDataWal",
+        KvsError::DecryptError => "DecryptError",
+        KvsError::ChecksumMismatch => "ChecksumMismatch",
+        KvsError::ServerError(..) => "ServerError",
+        KvsError::NotLeader(..) => "NotLeader",
+        KvsError::NoShard => "NoShard",
+        KvsError::InvalidBackup(..) => "InvalidBackup",
+        KvsError::InvalidRecord(..) => "InvalidRecord",
+        KvsError::UnsortedKey => "UnsortedKey",
+        KvsError::UnsafeRepair(..) => "UnsafeRepair",
+        KvsError::Shared(err) => error_kind(err),
+        KvsError::Poisoned => "Poisoned",
+        KvsError::Compacted => "Compacted",
+        KvsError::UnexpectedRecord(..) => "UnexpectedRecord",
+        KvsError::Locked(..) => "Locked",
+        KvsError::MessageTooLarge(..) => "MessageTooLarge",
+    }
+}
+
+fn series(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{{{}}}", name, labels)
+    }
+}
+
+/// answer one http request, only `GET /metrics` is served.
+pub(crate) fn handle_http(stream: TcpStream, metrics: &Metrics, store: &SharedKvStore) -> Result<()> {
+    // a scraper which never finishes its request isn't waited forever.
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // headers are ignored.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let path = parts.nth(1).and_then(|target| target.split('?').next());
+    let (status, body) = match (request_line.starts_with("GET "), path) {
+        (true, Some("/metrics")) => ("200 OK", metrics.render(store)?),
+        _ => ("404 Not Found", b"only /metrics is served\n".to_vec()),
+    };
+    let mut writer = stream;
+    write!(writer, "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                    Content-Length: {}\r\nConnection: close\r\n\r\n", status, body.len())?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use super::*;
+    use crate::{KvStore, KvsClient, KvsServer, Options};
+
+    fn scrape(addr: std::net::SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let store = SharedKvStore::new(KvStore::open(&tmpdir, Options::default()).unwrap());
+        let server = KvsServer::new(store.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics_addr = metrics_listener.local_addr().unwrap();
+        let metrics_server = server.clone();
+        std::thread::spawn(move || metrics_server.serve_metrics(metrics_listener));
+        std::thread::spawn(move || server.serve(listener));
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set(b"key", b"value").unwrap();
+        client.get(b"key").unwrap();
+        client.get(b"missing").unwrap();
+        assert!(client.remove(b"missing").is_err());
+        store.lock().compact().unwrap();
+
+        let response = scrape(metrics_addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("kvs_requests_total{op=\"get\"} 2\n"));
+        assert!(response.contains("kvs_requests_total{op=\"remove\"} 1\n"));
+        assert!(response.contains("kvs_request_duration_seconds_count{op=\"set\"} 1\n"));
+        assert!(response.contains("kvs_request_duration_seconds_bucket{op=\"get\",le=\"+Inf\"} 2\n"));
+        assert!(response.contains("kvs_errors_total{kind=\"NotFound\"} 1\n"));
+        assert!(response.contains("kvs_compaction_duration_seconds_count 1\n"));
+        let written = store.lock().stats.cmd_bytes_written;
+        assert!(written > 0);
+        assert!(response.contains(&format!("kvs_cmd_wal_written_bytes_total {}\n", written)));
+
+        assert!(scrape(metrics_addr, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
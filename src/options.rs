@@ -0,0 +1,48 @@
+use std::time::Duration;
+
+use crate::EncryptionKey;
+
+/// when written data is synced to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// sync on every write.
+    Always,
+    /// sync on a write if this long has passed since last sync.
+    /// a `SharedKvStore` also syncs in background once this long has
+    /// passed since an unsynced write, a plain `KvStore` only syncs on
+    /// writes.
+    Interval(Duration),
+    /// sync every N writes.
+    EveryWrites(u64),
+    /// leave it to OS. acknowledged writes may be lost on power failure.
+    #[default]
+    Never,
+}
+
+/// options to tune a KvStore when it's opened.
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// values not shorter than this are compressed before written
+    /// into cmd.wal. `None` disables compression.
+    pub compress_threshold: Option<usize>,
+    /// records in both logs are encrypted and authenticated
+    /// with this key. `None` keeps logs in plain text.
+    pub encryption_key: Option<EncryptionKey>,
+    /// how often cmd.wal and meta.wal are synced to disk.
+    pub sync_policy: SyncPolicy,
+    /// write a checkpoint of index every N writes, so recovery
+    /// only replays records after it. `None` disables it.
+    pub checkpoint_interval: Option<u64>,
+    /// values shorter than this are kept in memory, so `get`
+    /// doesn't read disk. `None` keeps only pointers in memory.
+    pub inline_threshold: Option<usize>,
+    /// max bytes of values kept in memory. values beyond it are
+    /// kept by pointers. `None` means unbounded.
+    pub inline_budget: Option<usize>,
+    /// max bytes of keys and values cached for reading
+    /// from cmd.wal. 0 disables cache.
+    pub cache_capacity: usize,
+    /// read sealed part of cmd.wal through memory map instead
+    /// of seeking and reading the file.
+    pub mmap_reads: bool,
+}
@@ -0,0 +1,70 @@
+use std::io::{Read, Write};
+
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+
+use crate::{Change, OnDiskCommand};
+use crate::error::Result;
+use crate::raft::Message;
+use crate::raftlog::NodeId;
+use crate::wal;
+
+/// a request sent by clients or followers.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    Get(Vec<u8>),
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    Keys,
+    // stream records after this sequence. the connection
+    // is used only for replication from then on.
+    Replicate(u64),
+    // stream changes of keys with a prefix, after a sequence if
+    // it's given or from now on.
+    Watch(Vec<u8>, Option<u64>),
+    // messages between raft nodes.
+    Raft(Message),
+    // membership changes of a raft cluster.
+    AddNode(NodeId, String),
+    RemoveNode(NodeId),
+    // back up the store into a directory on server.
+    Backup(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Response {
+    Value(Option<Vec<u8>>),
+    Keys(Vec<Vec<u8>>),
+    Done,
+    NotFound,
+    Error(String),
+    // plain records of cmd.wal in order.
+    Records(Vec<OnDiskCommand>),
+    // the follower is behind a compaction, records of the whole
+    // log are sent again and then `Synced` follows.
+    Reset,
+    // all records up to this sequence are sent since `Reset`.
+    Synced(u64),
+    Change(Change),
+    // sequence of a backup.
+    Backup(u64),
+    Raft(Message),
+    // address of leader if it's known.
+    NotLeader(Option<String>),
+}
+
+/// max bytes of a message received from network. it's far above
+/// batches of replication and chunks of snapshots.
+pub const MAX_MESSAGE_BYTES: u32 = 64 << 20;
+
+/// messages are framed the same way as log records.
+pub fn send<T: Serialize>(mut writer: impl Write, message: &T) -> Result<()> {
+    wal::write_wal_entry(&mut writer, message, None)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// a message longer than `MAX_MESSAGE_BYTES` is refused before
+/// it's read.
+pub fn receive<T: DeserializeOwned>(reader: impl Read) -> Result<T> {
+    wal::read_bounded_entry(reader, None, MAX_MESSAGE_BYTES)
+}
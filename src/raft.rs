@@ -0,0 +1,1027 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvStore, OnDiskCommand, OnDiskValue, Options};
+use crate::crypto;
+use crate::error::{KvsError, Result};
+use crate::protocol::{self, Request, Response};
+use crate::raftlog::{Entry, EntryData, NodeId, RaftLog, SnapshotMeta};
+
+// entries sent in one append message.
+const MAX_APPEND_ENTRIES: usize = 256;
+// how often a node checks timers.
+const TICK: Duration = Duration::from_millis(5);
+// timeout to connect or talk to a peer.
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+// how long a client waits for its write to be applied.
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// options of a raft node.
+#[derive(Debug, Clone)]
+pub struct RaftOptions {
+    /// interval of heartbeats from leader.
+    pub heartbeat: Duration,
+    /// a follower starts an election if it hears nothing from leader
+    /// for a random time between this and twice of it.
+    pub election_timeout: Duration,
+    /// take a snapshot once this many entries are applied since the
+    /// last one. the store is compacted and the entries are dropped.
+    pub snapshot_entries: u64,
+    /// a snapshot is sent to a follower in chunks of about this many
+    /// bytes of records.
+    pub snapshot_chunk_bytes: u64,
+    /// options of the underlying store.
+    pub store: Options,
+}
+
+impl Default for RaftOptions {
+    fn default() -> Self {
+        Self {
+            heartbeat: Duration::from_millis(50),
+            election_timeout: Duration::from_millis(300),
+            snapshot_entries: 10000,
+            snapshot_chunk_bytes: 1 << 20,
+            store: Options::default(),
+        }
+    }
+}
+
+/// messages between raft nodes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum Message {
+    Vote { term: u64, candidate: NodeId, last_index: u64, last_term: u64 },
+    VoteReply { term: u64, granted: bool },
+    Append {
+        term: u64,
+        leader: NodeId,
+        prev_index: u64,
+        prev_term: u64,
+        entries: Vec<Entry>,
+        commit: u64,
+    },
+    // on failure, `matched` is a hint where follower's log may match.
+    AppendReply { term: u64, success: bool, matched: u64 },
+    // live records of store between two offsets of compaction output.
+    // it's installed after the chunk which is `done`.
+    Snapshot {
+        term: u64,
+        leader: NodeId,
+        meta: SnapshotMeta,
+        offset: u64,
+        next: u64,
+        done: bool,
+        records: Vec<OnDiskCommand>,
+    },
+    // `matched` is set once snapshot is installed, before that `next`
+    // is the offset of chunk follower expects.
+    SnapshotReply { term: u64, matched: u64, next: u64 },
+}
+
+struct Progress {
+    next: u64,
+    matched: u64,
+    in_flight: bool,
+    last_sent: Option<Instant>,
+    // index of snapshot being sent and offset of its next chunk.
+    snapshot: Option<(u64, u64)>,
+    // read round of message in flight, and the latest answered one.
+    round_sent: u64,
+    round_acked: u64,
+}
+
+impl Progress {
+    fn new(next: u64) -> Self {
+        Self {
+            next,
+            matched: 0,
+            in_flight: false,
+            last_sent: None,
+            snapshot: None,
+            round_sent: 0,
+            round_acked: 0,
+        }
+    }
+}
+
+/// live records at the head of compacted cmd.wal up to `end`. they
+/// stay there until store is compacted again.
+struct Snapshot {
+    meta: SnapshotMeta,
+    compactions: u64,
+    end: u64,
+}
+
+/// chunks of a snapshot received so far.
+struct Receiving {
+    meta: SnapshotMeta,
+    next: u64,
+    records: Vec<OnDiskCommand>,
+}
+
+enum Role {
+    Follower,
+    Candidate(HashSet<NodeId>),
+    Leader(HashMap<NodeId, Progress>),
+}
+
+struct State {
+    store: KvStore,
+    log: RaftLog,
+    role: Role,
+    leader: Option<NodeId>,
+    commit: u64,
+    applied: u64,
+    election_deadline: Instant,
+    rng: u64,
+    // terms of entries proposed by clients waiting on this node.
+    waiting: HashMap<u64, u64>,
+    results: HashMap<u64, Result<()>>,
+    // the latest snapshot taken, sent to followers until log moves
+    // past it.
+    snapshot: Option<Snapshot>,
+    receiving: Option<Receiving>,
+    // a read waits for a majority to answer heartbeats of its round.
+    read_round: u64,
+}
+
+struct Node {
+    id: NodeId,
+    addr: String,
+    options: RaftOptions,
+    state: Mutex<State>,
+    applied: Condvar,
+    stopped: AtomicBool,
+    // idle connections to peers.
+    conns: Mutex<HashMap<String, TcpStream>>,
+}
+
+/// a member of a raft cluster. writes to its store are appended to
+/// a replicated log, and applied once a majority of members has them.
+/// only leader serves clients, gets are answered from its own store
+/// once a majority confirms it's still leader.
+pub struct RaftNode {
+    node: Arc<Node>,
+}
+
+impl RaftNode {
+    /// start a node with a given id and serve on a listener.
+    /// `peers` are the initial members including this node, which are
+    /// only used by a new cluster. a node joining an existing cluster
+    /// starts with no peers and waits to be added by leader.
+    pub fn start<P: AsRef<Path>>(id: NodeId, dir: P, listener: TcpListener,
+                                 peers: BTreeMap<NodeId, String>, options: RaftOptions) -> Result<Self> {
+        let addr = listener.local_addr()?.to_string();
+        let store = KvStore::open(dir.as_ref(), options.store.clone())?;
+        let cipher = options.store.encryption_key.as_ref().map(crypto::Cipher::new);
+        let log = RaftLog::open(dir.as_ref(), cipher, peers)?;
+        // records of store carry their index, so store is applied up to
+        // its latest sequence, or the snapshot it's installed from.
+        let applied = std::cmp::max(store.latest_seq, log.snapshot().index);
+        let mut state = State {
+            store,
+            log,
+            role: Role::Follower,
+            leader: None,
+            commit: applied,
+            applied,
+            election_deadline: Instant::now(),
+            rng: id ^ std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64),
+            waiting: HashMap::new(),
+            results: HashMap::new(),
+            snapshot: None,
+            receiving: None,
+            read_round: 0,
+        };
+        state.reset_deadline(options.election_timeout);
+
+        let node = Arc::new(Node {
+            id,
+            addr,
+            options,
+            state: Mutex::new(state),
+            applied: Condvar::new(),
+            stopped: AtomicBool::new(false),
+            conns: Mutex::new(HashMap::new()),
+        });
+        let ticker = node.clone();
+        std::thread::spawn(move || ticker.run_ticker());
+        let server = node.clone();
+        std::thread::spawn(move || server.serve(listener));
+        Ok(Self { node })
+    }
+
+    /// the address this node serves on.
+    pub fn addr(&self) -> &str {
+        &self.node.addr
+    }
+
+    /// whether this node is leader now.
+    pub fn is_leader(&self) -> bool {
+        matches!(self.node.lock().role, Role::Leader(..))
+    }
+
+    /// address of current leader if it's known.
+    pub fn leader(&self) -> Option<String> {
+        self.node.leader_addr(&self.node.lock())
+    }
+
+    /// stop serving and taking part in elections.
+    pub fn shutdown(&self) {
+        self.node.stopped.store(true, Ordering::SeqCst);
+        // wake up the listener.
+        let _ = TcpStream::connect(&self.node.addr);
+    }
+}
+
+impl Drop for RaftNode {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl State {
+    fn reset_deadline(&mut self, timeout: Duration) {
+        // xorshift is enough to spread timeouts of nodes.
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        let jitter = self.rng % (timeout.as_millis() as u64 + 1);
+        self.election_deadline = Instant::now() + timeout + Duration::from_millis(jitter);
+    }
+}
+
+impl Node {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn leader_addr(&self, state: &State) -> Option<String> {
+        state.leader.and_then(|leader| state.log.config().get(&leader).cloned())
+    }
+
+    fn serve(self: Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            if self.stopped() {
+                return;
+            }
+            if let Ok(stream) = stream {
+                let node = self.clone();
+                std::thread::spawn(move || {
+                    let _ = node.handle(stream);
+                });
+            }
+        }
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let request = match protocol::receive(&mut reader) {
+                Ok(request) => request,
+                Err(KvsError::IoError(ref err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            if self.stopped() {
+                return Ok(());
+            }
+            let response = match request {
+                Request::Raft(message) => self.step(message).map(Response::Raft),
+                Request::Get(key) => self.get(&key).map(Response::Value),
+                Request::Keys => self.keys().map(Response::Keys),
+                Request::Set(key, value) => self.propose(|index| EntryData::Write(OnDiskCommand {
+                    key,
+                    value: OnDiskValue::Content(index, value),
+                })).map(|_| Response::Done),
+                Request::Remove(key) => self.propose(|index| EntryData::Write(OnDiskCommand {
+                    key,
+                    value: OnDiskValue::DeletedKey(index),
+                })).map(|_| Response::Done),
+                Request::AddNode(id, addr) => self.change_config(|config| {
+                    config.insert(id, addr);
+                }).map(|_| Response::Done),
+                Request::RemoveNode(id) => self.change_config(|config| {
+                    config.remove(&id);
+                }).map(|_| Response::Done),
+                Request::Replicate(..) | Request::Watch(..) | Request::Backup(..) => {
+                    Ok(Response::Error("not supported by raft node".into()))
+                },
+            };
+            let response = match response {
+                Ok(response) => response,
+                Err(KvsError::NotFound) => Response::NotFound,
+                Err(KvsError::NotLeader(leader)) => Response::NotLeader(leader),
+                Err(err) => Response::Error(format!("{:?}", err)),
+            };
+            protocol::send(&mut writer, &response)?;
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut state = self.read_state()?;
+        state.store.get_bytes(key)
+    }
+
+    fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        let state = self.read_state()?;
+        Ok(state.store.keys())
+    }
+
+    /// wait until a majority answers heartbeats sent after the read
+    /// is asked, and store has every entry committed before it. so a
+    /// deposed leader which doesn't know it yet can't serve stale data.
+    fn read_state(&self) -> Result<MutexGuard<'_, State>> {
+        let mut state = self.lock();
+        let term = state.log.term();
+        state.read_round += 1;
+        let round = state.read_round;
+        if let Role::Leader(progress) = &mut state.role {
+            // heartbeats of this round are sent on next tick.
+            progress.values_mut().for_each(|p| p.last_sent = None);
+        }
+
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        let mut read_index = None;
+        loop {
+            let acked: Vec<_> = match &state.role {
+                Role::Leader(progress) if state.log.term() == term => progress.iter()
+                    .filter(|(_, p)| p.round_acked >= round)
+                    .map(|(&peer, _)| peer)
+                    .chain(std::iter::once(self.id))
+                    .collect(),
+                _ => return Err(KvsError::NotLeader(self.leader_addr(&state))),
+            };
+            // commit index of leader is known once an entry of its
+            // term is committed.
+            if read_index.is_none() && state.log.term_at(state.commit) == Some(term) {
+                read_index = Some(state.commit);
+            }
+            let applied = read_index.is_some_and(|index| state.applied >= index);
+            if applied && self.has_majority(&state, &acked) {
+                return Ok(state);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(KvsError::ServerError("timeout to confirm leadership".into()));
+            }
+            state = self.applied.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// append an entry made from its index, and wait until it's applied.
+    fn propose<F: FnOnce(u64) -> EntryData>(&self, make: F) -> Result<()> {
+        let mut state = self.lock();
+        if !matches!(state.role, Role::Leader(..)) {
+            return Err(KvsError::NotLeader(self.leader_addr(&state)));
+        }
+        let index = state.log.last_index() + 1;
+        let term = state.log.term();
+        state.log.append(vec![Entry { term, index, data: make(index) }])?;
+        state.waiting.insert(index, term);
+        self.advance_commit(&mut state)?;
+
+        let deadline = Instant::now() + PROPOSE_TIMEOUT;
+        loop {
+            if let Some(result) = state.results.remove(&index) {
+                return result;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                state.waiting.remove(&index);
+                return Err(KvsError::ServerError("timeout to commit".into()));
+            }
+            state = self.applied.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// add or remove one member at a time.
+    fn change_config<F: FnOnce(&mut BTreeMap<NodeId, String>)>(&self, change: F) -> Result<()> {
+        let mut config = {
+            let state = self.lock();
+            if state.log.config_index() > state.commit {
+                return Err(KvsError::ServerError("a membership change is in progress".into()));
+            }
+            state.log.config().clone()
+        };
+        change(&mut config);
+        self.propose(|_| EntryData::Config(config))
+    }
+
+    fn run_ticker(self: Arc<Self>) {
+        while !self.stopped() {
+            std::thread::sleep(TICK);
+            let mut state = self.lock();
+            let result = if matches!(state.role, Role::Leader(..)) {
+                self.replicate(&mut state)
+            } else if Instant::now() >= state.election_deadline {
+                self.start_election(&mut state)
+            } else {
+                Ok(())
+            };
+            if result.is_err() {
+                // failed to persist state, stop before going wrong.
+                self.stopped.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn start_election(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        state.reset_deadline(self.options.election_timeout);
+        // a node removed or not yet added never starts an election.
+        if !state.log.config().contains_key(&self.id) {
+            return Ok(());
+        }
+        let term = state.log.term() + 1;
+        state.log.save_vote(term, Some(self.id))?;
+        state.leader = None;
+        state.role = Role::Candidate(std::iter::once(self.id).collect());
+        if self.has_majority(state, &[self.id]) {
+            return self.become_leader(state);
+        }
+
+        let message = Message::Vote {
+            term,
+            candidate: self.id,
+            last_index: state.log.last_index(),
+            last_term: state.log.last_term(),
+        };
+        for (&peer, addr) in state.log.config().iter().filter(|(&peer, _)| peer != self.id) {
+            self.send(peer, addr.clone(), message.clone());
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, state: &mut State) -> Result<()> {
+        let next = state.log.last_index() + 1;
+        let progress = state.log.config().keys()
+            .filter(|&&peer| peer != self.id)
+            .map(|&peer| (peer, Progress::new(next)))
+            .collect();
+        state.role = Role::Leader(progress);
+        state.leader = Some(self.id);
+        state.receiving = None;
+        let term = state.log.term();
+        state.log.append(vec![Entry { term, index: next, data: EntryData::Noop }])?;
+        self.advance_commit(state)
+    }
+
+    /// send entries or snapshot to followers which are behind or
+    /// waiting for a heartbeat.
+    fn replicate(self: &Arc<Self>, state: &mut State) -> Result<()> {
+        let config = state.log.config().clone();
+        let last_index = state.log.last_index();
+        let round = state.read_round;
+        let mut sends = Vec::new();
+        if let Role::Leader(progress) = &mut state.role {
+            progress.retain(|peer, _| config.contains_key(peer));
+            for (&peer, addr) in config.iter().filter(|(&peer, _)| peer != self.id) {
+                let p = progress.entry(peer).or_insert_with(|| Progress::new(last_index + 1));
+                let due = p.last_sent.is_none_or(|sent| sent.elapsed() >= self.options.heartbeat);
+                if !p.in_flight && (due || p.next <= last_index) {
+                    p.in_flight = true;
+                    p.last_sent = Some(Instant::now());
+                    p.round_sent = round;
+                    sends.push((peer, addr.clone(), p.next, p.snapshot));
+                }
+            }
+        }
+
+        for (peer, addr, next, sending) in sends {
+            let message = if next <= state.log.snapshot().index {
+                let (meta, end) = self.snapshot(state)?;
+                // a follower starts over when a newer snapshot is taken.
+                let offset = match sending {
+                    Some((index, offset)) if index == meta.index => offset,
+                    _ => 0,
+                };
+                let (records, chunk_end) = self.snapshot_chunk(state, offset, end)?;
+                if let Role::Leader(progress) = &mut state.role {
+                    if let Some(p) = progress.get_mut(&peer) {
+                        p.snapshot = Some((meta.index, offset));
+                    }
+                }
+                Message::Snapshot {
+                    term: state.log.term(),
+                    leader: self.id,
+                    meta,
+                    offset,
+                    next: chunk_end,
+                    done: chunk_end >= end,
+                    records,
+                }
+            } else {
+                let prev_index = next - 1;
+                Message::Append {
+                    term: state.log.term(),
+                    leader: self.id,
+                    prev_index,
+                    prev_term: state.log.term_at(prev_index).unwrap_or(0),
+                    entries: state.log.entries_from(next, MAX_APPEND_ENTRIES),
+                    commit: state.commit,
+                }
+            };
+            self.send(peer, addr, message);
+        }
+        Ok(())
+    }
+
+    /// call a peer in background, its reply is handled when it comes.
+    fn send(self: &Arc<Self>, peer: NodeId, addr: String, message: Message) {
+        let node = self.clone();
+        std::thread::spawn(move || {
+            let reply = node.call(&addr, &message);
+            let mut state = node.lock();
+            if let Role::Leader(progress) = &mut state.role {
+                if let Some(p) = progress.get_mut(&peer) {
+                    p.in_flight = false;
+                }
+            }
+            if let Ok(reply) = reply {
+                if node.handle_reply(&mut state, peer, reply).is_err() {
+                    node.stopped.store(true, Ordering::SeqCst);
+                }
+            }
+        });
+    }
+
+    fn call(&self, addr: &str, message: &Message) -> Result<Message> {
+        let cached = self.conns.lock().unwrap().remove(addr);
+        let stream = match cached {
+            Some(stream) => stream,
+            None => {
+                let sock_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, "no address of peer")
+                })?;
+                let stream = TcpStream::connect_timeout(&sock_addr, RPC_TIMEOUT)?;
+                stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+                stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                stream
+            },
+        };
+        protocol::send(BufWriter::new(&stream), &Request::Raft(message.clone()))?;
+        let response = protocol::receive(BufReader::new(&stream))?;
+        self.conns.lock().unwrap().insert(addr.to_string(), stream);
+        match response {
+            Response::Raft(reply) => Ok(reply),
+            Response::Error(message) => Err(KvsError::ServerError(message)),
+            response => Err(KvsError::ServerError(format!("unexpected response {:?}", response))),
+        }
+    }
+
+    /// a message with a newer term turns this node into follower.
+    fn observe_term(&self, state: &mut State, term: u64) -> Result<()> {
+        if term > state.log.term() {
+            state.log.save_vote(term, None)?;
+            state.role = Role::Follower;
+            state.leader = None;
+        }
+        Ok(())
+    }
+
+    /// handle a message from a peer and reply it.
+    fn step(&self, message: Message) -> Result<Message> {
+        let mut state = self.lock();
+        let state = &mut *state;
+        match message {
+            Message::Vote { term, candidate, last_index, last_term } => {
+                self.observe_term(state, term)?;
+                let granted = term == state.log.term()
+                    && state.log.voted_for().is_none_or(|voted| voted == candidate)
+                    && (last_term, last_index) >= (state.log.last_term(), state.log.last_index());
+                if granted {
+                    state.log.save_vote(term, Some(candidate))?;
+                    state.reset_deadline(self.options.election_timeout);
+                }
+                Ok(Message::VoteReply { term: state.log.term(), granted })
+            },
+            Message::Append { term, leader, prev_index, prev_term, entries, commit } => {
+                if term < state.log.term() {
+                    return Ok(Message::AppendReply { term: state.log.term(), success: false, matched: 0 });
+                }
+                self.follow(state, term, leader)?;
+                self.append_entries(state, prev_index, prev_term, entries, commit)
+            },
+            Message::Snapshot { term, leader, meta, offset, next, done, records } => {
+                if term < state.log.term() {
+                    return Ok(Message::SnapshotReply { term: state.log.term(), matched: 0, next: 0 });
+                }
+                self.follow(state, term, leader)?;
+                if meta.index <= state.applied {
+                    state.receiving = None;
+                    return Ok(Message::SnapshotReply { term, matched: meta.index, next: 0 });
+                }
+                self.receive_snapshot(state, meta, offset, next, done, records)
+            },
+            _ => Err(KvsError::ServerError("unexpected message".into())),
+        }
+    }
+
+    fn follow(&self, state: &mut State, term: u64, leader: NodeId) -> Result<()> {
+        self.observe_term(state, term)?;
+        state.role = Role::Follower;
+        state.leader = Some(leader);
+        state.reset_deadline(self.options.election_timeout);
+        Ok(())
+    }
+
+    fn append_entries(&self, state: &mut State, mut prev_index: u64, mut prev_term: u64,
+                      mut entries: Vec<Entry>, commit: u64) -> Result<Message> {
+        let term = state.log.term();
+        let snapshot = state.log.snapshot().clone();
+        if prev_index < snapshot.index {
+            // entries in snapshot are committed, so they match.
+            let skip = std::cmp::min((snapshot.index - prev_index) as usize, entries.len());
+            entries.drain(..skip);
+            prev_index = snapshot.index;
+            prev_term = snapshot.term;
+        }
+        if state.log.term_at(prev_index) != Some(prev_term) {
+            let matched = std::cmp::min(state.log.last_index(), prev_index.saturating_sub(1));
+            return Ok(Message::AppendReply { term, success: false, matched });
+        }
+
+        let matched = prev_index + entries.len() as u64;
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            match state.log.term_at(entry.index) {
+                Some(term) if term == entry.term && new_entries.is_empty() => continue,
+                Some(_) if new_entries.is_empty() => {
+                    state.log.truncate_from(entry.index)?;
+                    new_entries.push(entry);
+                },
+                _ => new_entries.push(entry),
+            }
+        }
+        state.log.append(new_entries)?;
+
+        if commit > state.commit {
+            state.commit = std::cmp::min(commit, matched);
+            self.apply(state)?;
+        }
+        Ok(Message::AppendReply { term, success: true, matched })
+    }
+
+    /// keep a chunk of snapshot, and install it after the last chunk.
+    /// a chunk which doesn't follow the received ones is refused with
+    /// the offset expected.
+    #[allow(clippy::too_many_arguments)]
+    fn receive_snapshot(&self, state: &mut State, meta: SnapshotMeta, offset: u64, next: u64,
+                        done: bool, records: Vec<OnDiskCommand>) -> Result<Message> {
+        let term = state.log.term();
+        if offset == 0 {
+            state.receiving = Some(Receiving { meta: meta.clone(), next: 0, records: Vec::new() });
+        }
+        let mut receiving = match state.receiving.take() {
+            Some(receiving) if receiving.meta.index == meta.index && receiving.next == offset => receiving,
+            other => {
+                let expected = other.as_ref()
+                    .filter(|receiving| receiving.meta.index == meta.index)
+                    .map_or(0, |receiving| receiving.next);
+                state.receiving = other;
+                return Ok(Message::SnapshotReply { term, matched: 0, next: expected });
+            },
+        };
+        receiving.records.extend(records);
+        if !done {
+            receiving.next = next;
+            state.receiving = Some(receiving);
+            return Ok(Message::SnapshotReply { term, matched: 0, next });
+        }
+        let matched = meta.index;
+        self.install_snapshot(state, meta, receiving.records)?;
+        Ok(Message::SnapshotReply { term, matched, next: 0 })
+    }
+
+    fn install_snapshot(&self, state: &mut State, meta: SnapshotMeta,
+                        records: Vec<OnDiskCommand>) -> Result<()> {
+        let keys = records.iter().map(|cmd| cmd.key.clone()).collect();
+        state.store.apply_replicated(records)?;
+        state.store.finish_sync(meta.index, Some(keys))?;
+        // store has to be durable before entries are dropped.
+        state.store.sync()?;
+        state.commit = std::cmp::max(state.commit, meta.index);
+        state.applied = meta.index;
+        state.log.compact(meta)
+    }
+
+    fn handle_reply(&self, state: &mut State, peer: NodeId, reply: Message) -> Result<()> {
+        match reply {
+            Message::VoteReply { term, granted } => {
+                self.observe_term(state, term)?;
+                let won = match &mut state.role {
+                    Role::Candidate(votes) if term == state.log.term() && granted => {
+                        votes.insert(peer);
+                        votes.iter().cloned().collect::<Vec<_>>()
+                    },
+                    _ => return Ok(()),
+                };
+                if self.has_majority(state, &won) {
+                    self.become_leader(state)?;
+                }
+            },
+            Message::AppendReply { term, success, matched } => {
+                self.observe_term(state, term)?;
+                if term != state.log.term() {
+                    return Ok(());
+                }
+                if let Role::Leader(progress) = &mut state.role {
+                    if let Some(p) = progress.get_mut(&peer) {
+                        p.round_acked = std::cmp::max(p.round_acked, p.round_sent);
+                        if success {
+                            p.matched = std::cmp::max(p.matched, matched);
+                            p.next = p.matched + 1;
+                        } else {
+                            p.next = std::cmp::max(p.matched + 1, std::cmp::min(p.next - 1, matched + 1));
+                        }
+                    }
+                }
+                self.advance_commit(state)?;
+                self.applied.notify_all();
+            },
+            Message::SnapshotReply { term, matched, next } => {
+                self.observe_term(state, term)?;
+                if term != state.log.term() {
+                    return Ok(());
+                }
+                if let Role::Leader(progress) = &mut state.role {
+                    if let Some(p) = progress.get_mut(&peer) {
+                        p.round_acked = std::cmp::max(p.round_acked, p.round_sent);
+                        if matched > 0 {
+                            p.matched = std::cmp::max(p.matched, matched);
+                            p.next = p.matched + 1;
+                            p.snapshot = None;
+                        } else {
+                            p.snapshot = p.snapshot.map(|(index, _)| (index, next));
+                        }
+                    }
+                }
+                // reads may wait for this answer.
+                self.applied.notify_all();
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    fn has_majority(&self, state: &State, nodes: &[NodeId]) -> bool {
+        let config = state.log.config();
+        let count = config.keys().filter(|id| nodes.contains(id)).count();
+        count * 2 > config.len()
+    }
+
+    /// commit the latest entry of current term stored by a majority.
+    fn advance_commit(&self, state: &mut State) -> Result<()> {
+        let progress = match &state.role {
+            Role::Leader(progress) => progress,
+            _ => return Ok(()),
+        };
+        let last_index = state.log.last_index();
+        for index in (state.commit + 1..=last_index).rev() {
+            if state.log.term_at(index) != Some(state.log.term()) {
+                break;
+            }
+            let stored: Vec<_> = progress.iter()
+                .filter(|(_, p)| p.matched >= index)
+                .map(|(&peer, _)| peer)
+                .chain(std::iter::once(self.id))
+                .collect();
+            if self.has_majority(state, &stored) {
+                state.commit = index;
+                break;
+            }
+        }
+        self.apply(state)
+    }
+
+    /// apply committed entries to store, and wake up their clients.
+    fn apply(&self, state: &mut State) -> Result<()> {
+        while state.applied < state.commit {
+            let index = state.applied + 1;
+            let entry = match state.log.entry(index) {
+                Some(entry) => entry.clone(),
+                None => break,
+            };
+            let result = match entry.data {
+                EntryData::Write(cmd) => {
+                    let missing = !state.store.location_finder.contains_key(&cmd.key);
+                    if matches!(cmd.value, OnDiskValue::DeletedKey(..)) && missing {
+                        Err(KvsError::NotFound)
+                    } else {
+                        state.store.apply_replicated(vec![cmd])?;
+                        Ok(())
+                    }
+                },
+                EntryData::Config(config) => {
+                    if !config.contains_key(&self.id) && matches!(state.role, Role::Leader(..)) {
+                        // removed leader leaves once the change is committed.
+                        state.role = Role::Follower;
+                        state.leader = None;
+                    }
+                    Ok(())
+                },
+                EntryData::Noop => Ok(()),
+            };
+            state.applied = index;
+            if let Some(term) = state.waiting.remove(&index) {
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(KvsError::ServerError("entry is lost by leader change".into()))
+                };
+                state.results.insert(index, result);
+            }
+        }
+        self.applied.notify_all();
+
+        if state.applied - state.log.snapshot().index >= self.options.snapshot_entries {
+            self.take_snapshot(state)?;
+        }
+        Ok(())
+    }
+
+    /// compact store, then drop applied entries. live records in
+    /// compacted cmd.wal are the snapshot.
+    fn take_snapshot(&self, state: &mut State) -> Result<()> {
+        state.store.compact()?;
+        let index = state.applied;
+        let meta = SnapshotMeta {
+            index,
+            term: state.log.term_at(index).unwrap_or(0),
+            config: state.log.config_at(index).clone(),
+        };
+        state.log.compact(meta.clone())?;
+        state.snapshot = Some(Snapshot {
+            meta,
+            compactions: state.store.compactions,
+            end: state.store.wal_cmd.fd.metadata()?.len(),
+        });
+        Ok(())
+    }
+
+    /// the latest snapshot and where its records end. a new one is
+    /// taken only if the log moved past it, or store is compacted
+    /// since.
+    fn snapshot(&self, state: &mut State) -> Result<(SnapshotMeta, u64)> {
+        let index = state.log.snapshot().index;
+        let compactions = state.store.compactions;
+        let current = state.snapshot.as_ref()
+            .is_some_and(|snapshot| snapshot.meta.index == index && snapshot.compactions == compactions);
+        if !current {
+            self.take_snapshot(state)?;
+        }
+        match &state.snapshot {
+            Some(snapshot) => Ok((snapshot.meta.clone(), snapshot.end)),
+            None => Err(KvsError::ServerError("no snapshot is taken".into())),
+        }
+    }
+
+    /// records of snapshot from an offset, about `snapshot_chunk_bytes`
+    /// of them, and the offset after them.
+    fn snapshot_chunk(&self, state: &State, offset: u64, end: u64) -> Result<(Vec<OnDiskCommand>, u64)> {
+        let mut reader = BufReader::new(&state.store.wal_cmd.fd);
+        let mut iter = state.store.wal_cmd.iter_from(&mut reader, offset);
+        let mut records = Vec::new();
+        let mut next = end;
+        for (at, OnDiskCommand{key, value}) in &mut iter {
+            let full = at - offset >= self.options.snapshot_chunk_bytes && !records.is_empty();
+            if at >= end || full {
+                next = std::cmp::min(at, end);
+                break;
+            }
+            records.push(OnDiskCommand { key, value: value.into_plain()? });
+        }
+        iter.finish()?;
+        Ok((records, next))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::KvsClient;
+
+    fn options() -> RaftOptions {
+        RaftOptions {
+            heartbeat: Duration::from_millis(20),
+            election_timeout: Duration::from_millis(150),
+            snapshot_entries: 8,
+            snapshot_chunk_bytes: 64,
+            ..RaftOptions::default()
+        }
+    }
+
+    fn wait_for<F: FnMut() -> bool>(mut cond: F) {
+        for _ in 0..1000 {
+            if cond() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timeout");
+    }
+
+    fn wait_leader<'a>(nodes: &[&'a RaftNode]) -> &'a RaftNode {
+        let mut leader = None;
+        wait_for(|| {
+            leader = nodes.iter().find(|node| node.is_leader()).cloned();
+            leader.is_some()
+        });
+        leader.unwrap()
+    }
+
+    fn local_get(node: &RaftNode, key: &[u8]) -> Option<Vec<u8>> {
+        node.node.lock().store.get_bytes(key).unwrap()
+    }
+
+    #[test]
+    fn test_cluster() {
+        let dirs: Vec<_> = (0..4).map(|_| tempfile::tempdir().unwrap()).collect();
+        let listeners: Vec<_> = (0..4).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let peers: BTreeMap<_, _> = (1..=3)
+            .map(|id| (id, listeners[id as usize - 1].local_addr().unwrap().to_string()))
+            .collect();
+        let mut listeners = listeners.into_iter();
+        let nodes: Vec<_> = (1..=3).map(|id| {
+            RaftNode::start(id, &dirs[id as usize - 1], listeners.next().unwrap(),
+                            peers.clone(), options()).unwrap()
+        }).collect();
+
+        let leader = wait_leader(&nodes.iter().collect::<Vec<_>>());
+        let mut client = KvsClient::connect(leader.addr()).unwrap();
+        for i in 0..20 {
+            client.set(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        client.remove(b"key0").unwrap();
+        assert!(matches!(client.remove(b"key0"), Err(KvsError::NotFound)));
+        assert_eq!(client.get(b"key1").unwrap(), Some(b"value".to_vec()));
+
+        let follower = nodes.iter().find(|node| !node.is_leader()).unwrap();
+        match KvsClient::connect(follower.addr()).unwrap().set(b"key1", b"value") {
+            Err(KvsError::NotLeader(Some(addr))) => assert_eq!(addr, leader.addr()),
+            other => panic!("unexpected result {:?}", other),
+        }
+        wait_for(|| nodes.iter().all(|node| local_get(node, b"key19").is_some()));
+        assert!(nodes.iter().all(|node| local_get(node, b"key0").is_none()));
+
+        // the rest elect a new leader.
+        leader.shutdown();
+        let old_leader = leader.node.id;
+        let rest: Vec<_> = nodes.iter().filter(|node| node.node.id != old_leader).collect();
+        let leader = wait_leader(&rest);
+        let mut client = KvsClient::connect(leader.addr()).unwrap();
+        client.set(b"key20", b"value").unwrap();
+        wait_for(|| rest.iter().all(|node| local_get(node, b"key20").is_some()));
+
+        // a new member catches up from a snapshot.
+        let node4 = RaftNode::start(4, &dirs[3], listeners.next().unwrap(),
+                                    BTreeMap::new(), options()).unwrap();
+        client.add_node(4, node4.addr()).unwrap();
+        wait_for(|| local_get(&node4, b"key20").is_some());
+        assert_eq!(local_get(&node4, b"key0"), None);
+        assert_eq!(local_get(&node4, b"key1"), Some(b"value".to_vec()));
+        assert!(node4.node.lock().log.snapshot().index > 0);
+
+        client.remove_node(old_leader).unwrap();
+        assert_eq!(leader.node.lock().log.config().len(), 3);
+        client.set(b"key21", b"value").unwrap();
+        wait_for(|| local_get(&node4, b"key21").is_some());
+    }
+
+    #[test]
+    fn test_read_from_deposed_leader() {
+        let dirs: Vec<_> = (0..3).map(|_| tempfile::tempdir().unwrap()).collect();
+        let listeners: Vec<_> = (0..3).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let peers: BTreeMap<_, _> = (1..=3)
+            .map(|id| (id, listeners[id as usize - 1].local_addr().unwrap().to_string()))
+            .collect();
+        let nodes: Vec<_> = listeners.into_iter().zip(1..=3).map(|(listener, id)| {
+            RaftNode::start(id, &dirs[id as usize - 1], listener, peers.clone(), options()).unwrap()
+        }).collect();
+
+        let leader = wait_leader(&nodes.iter().collect::<Vec<_>>());
+        let mut client = KvsClient::connect(leader.addr()).unwrap();
+        client.set(b"key", b"value").unwrap();
+        assert_eq!(client.get(b"key").unwrap(), Some(b"value".to_vec()));
+
+        // the leader can't hear from a majority, which may have elected
+        // another leader, so it doesn't answer from its own store.
+        nodes.iter().filter(|node| !node.is_leader()).for_each(RaftNode::shutdown);
+        assert!(leader.is_leader());
+        assert!(matches!(client.get(b"key"), Err(KvsError::ServerError(..))));
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::OnDiskCommand;
+use crate::crypto::Cipher;
+use crate::error::Result;
+use crate::wal::{WalEntry, WalLog};
+
+const LOG_FILE: &str = "raft.wal";
+const LOG_TMP_FILE: &str = "raft.wal.tmp";
+
+/// id of a node in a raft cluster.
+pub type NodeId = u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum EntryData {
+    // appended by a new leader to commit entries of previous terms.
+    Noop,
+    // the sequence of command is index of its entry.
+    Write(OnDiskCommand),
+    // members of cluster from this entry on.
+    Config(BTreeMap<NodeId, String>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub term: u64,
+    pub index: u64,
+    pub data: EntryData,
+}
+
+/// entries up to index are applied to store and dropped from log.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SnapshotMeta {
+    pub index: u64,
+    pub term: u64,
+    pub config: BTreeMap<NodeId, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum LogRecord {
+    Vote(u64, Option<NodeId>),
+    Entry(Entry),
+    // entries from this index are dropped.
+    Truncate(u64),
+    Snapshot(SnapshotMeta),
+}
+
+impl WalEntry for LogRecord {
+    const LOG_ID: u8 = 4;
+}
+
+/// persistent state of a raft node: current term, vote and entries
+/// after the latest snapshot. every change is synced before return.
+pub struct RaftLog {
+    dir: PathBuf,
+    wal: WalLog<LogRecord>,
+    writer: BufWriter<File>,
+    term: u64,
+    voted_for: Option<NodeId>,
+    snapshot: SnapshotMeta,
+    entries: Vec<Entry>,
+}
+
+impl RaftLog {
+    /// open the log in a directory. a new log starts with a given config.
+    pub fn open(dir: &Path, cipher: Option<Cipher>, config: BTreeMap<NodeId, String>) -> Result<Self> {
+        let path = dir.join(LOG_FILE);
+        let fresh = !path.exists();
+        let fd = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let mut reader = BufReader::new(fd.try_clone()?);
+        let mut log = Self {
+            dir: dir.to_path_buf(),
+            wal: WalLog::with_cipher(fd.try_clone()?, cipher),
+            writer: BufWriter::new(fd),
+            term: 0,
+            voted_for: None,
+            snapshot: SnapshotMeta { config, ..SnapshotMeta::default() },
+            entries: Vec::new(),
+        };
+        if !fresh {
+            let mut iter = log.wal.iter(&mut reader);
+            for (_, record) in &mut iter {
+                match record {
+                    LogRecord::Vote(term, voted_for) => {
+                        log.term = term;
+                        log.voted_for = voted_for;
+                    },
+                    // entries covered by snapshot are already applied.
+                    LogRecord::Entry(entry) if entry.index <= log.snapshot.index => {},
+                    LogRecord::Entry(entry) => {
+                        log.entries.truncate(log.position(entry.index));
+                        log.entries.push(entry);
+                    },
+                    LogRecord::Truncate(index) => {
+                        let pos = log.position(index);
+                        log.entries.truncate(pos);
+                    },
+                    LogRecord::Snapshot(meta) => {
+                        log.entries.retain(|entry| entry.index > meta.index);
+                        log.snapshot = meta;
+                    },
+                }
+            }
+            iter.finish()?;
+        }
+        // drop a partial written record at the tail, if any.
+        log.rewrite()?;
+        Ok(log)
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn voted_for(&self) -> Option<NodeId> {
+        self.voted_for
+    }
+
+    pub fn snapshot(&self) -> &SnapshotMeta {
+        &self.snapshot
+    }
+
+    pub fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// term of entry at index, `None` if it's not in log.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            Some(self.snapshot.term)
+        } else {
+            self.entry(index).map(|entry| entry.term)
+        }
+    }
+
+    pub fn entry(&self, index: u64) -> Option<&Entry> {
+        if index <= self.snapshot.index {
+            return None;
+        }
+        self.entries.get(self.position(index))
+    }
+
+    /// position of an index in entries. indices in snapshot are before
+    /// the first entry.
+    fn position(&self, index: u64) -> usize {
+        index.saturating_sub(self.snapshot.index + 1) as usize
+    }
+
+    /// at most `max` entries from a given index.
+    pub fn entries_from(&self, index: u64, max: usize) -> Vec<Entry> {
+        let start = self.position(index);
+        self.entries.iter().skip(start).take(max).cloned().collect()
+    }
+
+    /// members from the latest config in log, committed or not.
+    pub fn config(&self) -> &BTreeMap<NodeId, String> {
+        self.config_at(self.last_index())
+    }
+
+    /// members at a given index.
+    pub fn config_at(&self, index: u64) -> &BTreeMap<NodeId, String> {
+        self.entries.iter().rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.data {
+                EntryData::Config(config) => Some(config),
+                _ => None,
+            })
+            .unwrap_or(&self.snapshot.config)
+    }
+
+    /// index of the latest config in log.
+    pub fn config_index(&self) -> u64 {
+        self.entries.iter().rev()
+            .find(|entry| matches!(entry.data, EntryData::Config(..)))
+            .map_or(self.snapshot.index, |entry| entry.index)
+    }
+
+    pub fn save_vote(&mut self, term: u64, voted_for: Option<NodeId>) -> Result<()> {
+        self.persist(&[LogRecord::Vote(term, voted_for)])?;
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    /// append entries following the last one.
+    pub fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let records: Vec<_> = entries.iter().cloned().map(LogRecord::Entry).collect();
+        self.persist(&records)?;
+        self.entries.extend(entries);
+        Ok(())
+    }
+
+    /// drop entries from a given index, which conflict with leader.
+    /// entries in snapshot are kept, only the following ones are dropped.
+    pub fn truncate_from(&mut self, index: u64) -> Result<()> {
+        self.persist(&[LogRecord::Truncate(index)])?;
+        let pos = self.position(index);
+        self.entries.truncate(pos);
+        Ok(())
+    }
+
+    /// drop entries covered by a snapshot. the following entries are
+    /// kept only if the log has the last entry of snapshot.
+    pub fn compact(&mut self, meta: SnapshotMeta) -> Result<()> {
+        if self.term_at(meta.index) == Some(meta.term) {
+            self.entries.retain(|entry| entry.index > meta.index);
+        } else {
+            self.entries.clear();
+        }
+        self.snapshot = meta;
+        self.rewrite()
+    }
+
+    fn persist(&mut self, records: &[LogRecord]) -> Result<()> {
+        self.wal.append_batch(&mut self.writer, records)?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// replace log file with current state.
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp_path = self.dir.join(LOG_TMP_FILE);
+        let fd = OpenOptions::new().read(true).write(true).create(true).truncate(true)
+            .open(&tmp_path)?;
+        let mut records = vec![
+            LogRecord::Vote(self.term, self.voted_for),
+            LogRecord::Snapshot(self.snapshot.clone()),
+        ];
+        records.extend(self.entries.iter().cloned().map(LogRecord::Entry));
+        let mut writer = BufWriter::new(fd.try_clone()?);
+        self.wal.append_batch(&mut writer, &records)?;
+        writer.flush()?;
+        std::mem::drop(writer);
+        fd.sync_all()?;
+        std::fs::rename(&tmp_path, self.dir.join(LOG_FILE))?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.wal.fd = fd.try_clone()?;
+        self.writer = BufWriter::new(fd);
+        self.writer.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(term: u64, index: u64) -> Entry {
+        Entry { term, index, data: EntryData::Noop }
+    }
+
+    #[test]
+    fn test_raft_log_reopen() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut config = BTreeMap::new();
+        config.insert(1, "127.0.0.1:4001".to_string());
+        let mut log = RaftLog::open(tmpdir.path(), None, config.clone()).unwrap();
+        log.save_vote(2, Some(1)).unwrap();
+        log.append((1..=5).map(|index| entry(1, index)).collect()).unwrap();
+        log.truncate_from(4).unwrap();
+        log.append(vec![entry(2, 4)]).unwrap();
+        assert_eq!((log.last_index(), log.last_term()), (4, 2));
+
+        config.insert(2, "127.0.0.1:4002".to_string());
+        log.append(vec![Entry { term: 2, index: 5, data: EntryData::Config(config.clone()) }]).unwrap();
+        log.compact(SnapshotMeta { index: 3, term: 1, config: log.config_at(3).clone() }).unwrap();
+        std::mem::drop(log);
+
+        let log = RaftLog::open(tmpdir.path(), None, BTreeMap::new()).unwrap();
+        assert_eq!((log.term(), log.voted_for()), (2, Some(1)));
+        assert_eq!(log.snapshot().index, 3);
+        assert_eq!(log.term_at(2), None);
+        assert_eq!(log.term_at(3), Some(1));
+        assert_eq!(log.term_at(4), Some(2));
+        assert_eq!(log.config(), &config);
+        assert_eq!(log.config_at(4).len(), 1);
+        assert_eq!(log.config_index(), 5);
+        assert_eq!(log.entries_from(4, 10).len(), 2);
+    }
+
+    #[test]
+    fn test_raft_log_before_snapshot() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut log = RaftLog::open(tmpdir.path(), None, BTreeMap::new()).unwrap();
+        log.append((1..=5).map(|index| entry(1, index)).collect()).unwrap();
+        log.compact(SnapshotMeta { index: 3, term: 1, config: BTreeMap::new() }).unwrap();
+        // records at or below snapshot come from a stale leader.
+        log.persist(&[LogRecord::Entry(entry(1, 2)), LogRecord::Truncate(1)]).unwrap();
+        log.append(vec![entry(2, 4)]).unwrap();
+        std::mem::drop(log);
+
+        let mut log = RaftLog::open(tmpdir.path(), None, BTreeMap::new()).unwrap();
+        assert_eq!((log.last_index(), log.last_term()), (4, 2));
+        assert_eq!(log.entry(2).map(|entry| entry.index), None);
+        log.truncate_from(3).unwrap();
+        assert_eq!((log.last_index(), log.last_term()), (3, 1));
+        assert_eq!(log.term_at(3), Some(1));
+    }
+}
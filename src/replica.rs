@@ -0,0 +1,294 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::{KvStore, OnDiskCommand, OnDiskValue, SharedKvStore, Value};
+use crate::error::{KvsError, Result};
+use crate::protocol::{self, Request, Response};
+
+// records read from log for one message, sent or skipped. store is
+// unlocked between them.
+const BATCH_RECORDS: usize = 1024;
+// bytes of keys and values sent in one message, so a batch of large
+// values stays below the limit of messages.
+const BATCH_BYTES: usize = protocol::MAX_MESSAGE_BYTES as usize / 4;
+// how often leader checks new records for a caught up follower.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// position of a follower in cmd.wal of leader.
+struct Cursor {
+    // offsets are only valid within the same compaction.
+    compactions: Option<u64>,
+    offset: u64,
+    // records up to this sequence are sent to follower.
+    applied_seq: u64,
+    resetting: bool,
+    // records after offset are left to read.
+    scanning: bool,
+}
+
+/// stream records of a store to a follower, until it's disconnected.
+pub(crate) fn feed(store: &SharedKvStore, mut writer: impl Write, after_seq: u64) -> Result<()> {
+    let mut cursor = Cursor {
+        compactions: None,
+        offset: 0,
+        applied_seq: after_seq,
+        resetting: false,
+        scanning: false,
+    };
+    loop {
+        let response = store.lock().replication_feed(&mut cursor)?;
+        match response {
+            Some(response) => protocol::send(&mut writer, &response)?,
+            None if cursor.scanning => {},
+            None => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+}
+
+impl KvStore {
+    /// next message for a follower at the cursor, `None` if it's
+    /// caught up or the records read are all sent before.
+    fn replication_feed(&mut self, cursor: &mut Cursor) -> Result<Option<Response>> {
+        if cursor.compactions != Some(self.compactions) {
+            cursor.compactions = Some(self.compactions);
+            cursor.offset = 0;
+            if cursor.resetting || cursor.applied_seq < self.compacted_seq {
+                // removals dropped by compaction are never sent, so the
+                // follower drops its keys which are not in current log.
+                cursor.resetting = true;
+                return Ok(Some(Response::Reset));
+            }
+        }
+
+        let mut records = Vec::new();
+        let mut next = self.wal_cmd.fd.metadata()?.len();
+        let mut reader = BufReader::new(&self.wal_cmd.fd);
+        let mut iter = self.wal_cmd.iter_from(&mut reader, cursor.offset);
+        let mut bytes = 0;
+        cursor.scanning = false;
+        for (read, (offset, OnDiskCommand{key, value})) in (&mut iter).enumerate() {
+            if read == BATCH_RECORDS || bytes >= BATCH_BYTES {
+                next = offset;
+                cursor.scanning = true;
+                break;
+            }
+            // records are read again from start of log after compaction.
+            if cursor.resetting || value.sequence() > cursor.applied_seq {
+                let value = value.into_plain()?;
+                bytes += key.len() + match &value {
+                    OnDiskValue::Content(_, data) => data.len(),
+                    _ => 0,
+                };
+                records.push(OnDiskCommand{ key, value });
+            }
+        }
+        iter.finish()?;
+        cursor.offset = next;
+
+        if !records.is_empty() {
+            if !cursor.resetting {
+                let seq = records.iter().map(|cmd| cmd.value.sequence()).max().unwrap_or(0);
+                cursor.applied_seq = std::cmp::max(cursor.applied_seq, seq);
+            }
+            return Ok(Some(Response::Records(records)));
+        }
+        if cursor.scanning {
+            return Ok(None);
+        }
+        if cursor.resetting || cursor.applied_seq < self.latest_seq {
+            // sequences of removed keys may be missing from log.
+            cursor.resetting = false;
+            cursor.applied_seq = self.latest_seq;
+            return Ok(Some(Response::Synced(self.latest_seq)));
+        }
+        Ok(None)
+    }
+
+    /// append records of leader with their sequences, and index
+    /// them the same way as they are replayed.
+    pub(crate) fn apply_replicated(&mut self, records: Vec<OnDiskCommand>) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let writes = records.len() as u64;
+        let stored = self.append_cmd_wal(records)?;
+        let mut applied = HashMap::new();
+        for (offset, OnDiskCommand{key, value}) in stored {
+            self.latest_seq = std::cmp::max(self.latest_seq, value.sequence());
+            self.fill_from_cmd(&mut applied, key, value, offset)?;
+        }
+        self.wal_meta_writer.flush()?;
+
+        for (key, (_, value)) in applied {
+            match value {
+                Value::Deleted => self.index_remove(&key),
+                // budget may be used up by previous values in batch.
+                Value::Content(offset, content) if !self.inline_allowed(content.len()) => {
+                    self.index_insert(key, Value::Location(offset));
+                },
+                value => self.index_insert(key, value),
+            }
+        }
+        self.finish_writes(writes)
+    }
+
+    /// the follower has all records of leader up to a sequence.
+    /// after a reset, keys not sent again are removed.
+    pub(crate) fn finish_sync(&mut self, seq: u64, reset_keys: Option<HashSet<Vec<u8>>>) -> Result<()> {
+        if let Some(keys) = reset_keys {
+            let removed = self.location_finder.keys()
+                .filter(|key| !keys.contains(*key))
+                .map(|key| OnDiskCommand{ key: key.clone(), value: OnDiskValue::DeletedKey(seq) })
+                .collect();
+            self.apply_replicated(removed)?;
+        }
+        if seq > self.latest_seq {
+            self.latest_seq = seq;
+            self.hint_dirty = true;
+        }
+        Ok(())
+    }
+}
+
+impl SharedKvStore {
+    /// replicate a leader into this store. records after the latest
+    /// sequence of this store are requested and applied as they come.
+    /// it returns only when the connection fails.
+    pub fn follow<A: ToSocketAddrs>(&self, leader: A) -> Result<()> {
+        let stream = TcpStream::connect(leader)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let latest_seq = self.lock().latest_seq;
+        protocol::send(&stream, &Request::Replicate(latest_seq))?;
+
+        // keys sent since a reset, others are removed once synced.
+        let mut reset_keys: Option<HashSet<Vec<u8>>> = None;
+        loop {
+            match protocol::receive(&mut reader)? {
+                Response::Records(records) => {
+                    if let Some(keys) = reset_keys.as_mut() {
+                        keys.extend(records.iter().map(|cmd| cmd.key.clone()));
+                    }
+                    self.lock().apply_replicated(records)?;
+                },
+                Response::Reset => reset_keys = Some(HashSet::new()),
+                Response::Synced(seq) => self.lock().finish_sync(seq, reset_keys.take())?,
+                Response::Error(message) => return Err(KvsError::ServerError(message)),
+                response => {
+                    return Err(KvsError::ServerError(format!("unexpected response {:?}", response)));
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+    use super::*;
+    use crate::{KvsClient, KvsServer, Options};
+
+    fn serve(server: KvsServer) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || server.serve(listener));
+        addr
+    }
+
+    fn wait_for<F: FnMut() -> bool>(mut cond: F) {
+        for _ in 0..500 {
+            if cond() {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("timeout");
+    }
+
+    #[test]
+    fn test_follow_leader() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader = SharedKvStore::new(KvStore::open(&leader_dir, Options::default()).unwrap());
+        leader.set(b"key1", b"value1").unwrap();
+        leader.set(b"key2", b"value2").unwrap();
+        leader.remove(b"key1").unwrap();
+        let leader_addr = serve(KvsServer::new(leader.clone()));
+
+        let follower_dir = tempfile::tempdir().unwrap();
+        let follower = SharedKvStore::new(KvStore::open(&follower_dir, Options::default()).unwrap());
+        let replica = follower.clone();
+        std::thread::spawn(move || replica.follow(leader_addr));
+        wait_for(|| follower.get(b"key2").unwrap().is_some());
+        assert_eq!(follower.get(b"key1").unwrap(), None);
+
+        let mut client = KvsClient::connect(leader_addr).unwrap();
+        client.set(b"key3", b"value3").unwrap();
+        wait_for(|| follower.get(b"key3").unwrap().is_some());
+        assert_eq!(follower.lock().latest_seq, 4);
+
+        // follower serves gets only.
+        let mut client = KvsClient::connect(serve(KvsServer::follower(follower.clone()))).unwrap();
+        assert_eq!(client.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+        assert!(matches!(client.set(b"key4", b"value4"), Err(KvsError::ServerError(_))));
+        assert!(matches!(client.remove(b"key2"), Err(KvsError::ServerError(_))));
+    }
+
+    #[test]
+    fn test_resync_after_compaction() {
+        let leader_dir = tempfile::tempdir().unwrap();
+        let leader = SharedKvStore::new(KvStore::open(&leader_dir, Options::default()).unwrap());
+        leader.set(b"key1", b"value1").unwrap();
+        leader.set(b"key2", b"value2").unwrap();
+        leader.lock().sync().unwrap();
+
+        // a follower stopped at sequence 2.
+        let follower_dir = tempfile::tempdir().unwrap();
+        for name in &["cmd.wal", "meta.wal"] {
+            std::fs::copy(leader_dir.path().join(name), follower_dir.path().join(name)).unwrap();
+        }
+
+        // removal of key1 is dropped by compaction.
+        leader.remove(b"key1").unwrap();
+        leader.set(b"key3", b"value3").unwrap();
+        leader.lock().compact().unwrap();
+        let leader_addr = serve(KvsServer::new(leader.clone()));
+
+        let follower = SharedKvStore::new(KvStore::open(&follower_dir, Options::default()).unwrap());
+        assert_eq!(follower.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+        let replica = follower.clone();
+        std::thread::spawn(move || replica.follow(leader_addr));
+        wait_for(|| follower.lock().latest_seq == 4);
+        assert_eq!(follower.get(b"key1").unwrap(), None);
+        assert_eq!(follower.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+        assert_eq!(follower.get(b"key3").unwrap(), Some(b"value3".to_vec()));
+    }
+
+    #[test]
+    fn test_feed_skips_in_batches() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut store = KvStore::open(&tmpdir, Options::default()).unwrap();
+        let count = BATCH_RECORDS * 2 + 1;
+        for i in 0..count {
+            store.set_bytes(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        let mut cursor = Cursor {
+            compactions: None,
+            offset: 0,
+            applied_seq: count as u64 - 1,
+            resetting: false,
+            scanning: false,
+        };
+        // the store is given back after each batch of skipped records.
+        for _ in 0..2 {
+            assert!(store.replication_feed(&mut cursor).unwrap().is_none());
+            assert!(cursor.scanning);
+        }
+        match store.replication_feed(&mut cursor).unwrap() {
+            Some(Response::Records(records)) => assert_eq!(records.len(), 1),
+            other => panic!("unexpected response {:?}", other),
+        }
+        assert!(store.replication_feed(&mut cursor).unwrap().is_none());
+        assert!(!cursor.scanning);
+    }
+}
@@ -0,0 +1,210 @@
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::SharedKvStore;
+use crate::error::{KvsError, Result};
+use crate::metrics::{self, Metrics};
+use crate::protocol::{self, Request, Response};
+use crate::replica;
+
+/// serve a store over tcp, each connection is handled by a thread.
+#[derive(Clone)]
+pub struct KvsServer {
+    store: SharedKvStore,
+    read_only: bool,
+    // backups requested by clients are written only under it.
+    backup_root: Option<PathBuf>,
+    // shared by clones serving connections and metrics.
+    metrics: Arc<Metrics>,
+}
+
+impl KvsServer {
+    /// serve gets and writes of a store, and followers replicating it.
+    pub fn new(store: SharedKvStore) -> Self {
+        Self { store, read_only: false, backup_root: None, metrics: Arc::default() }
+    }
+
+    /// serve only gets of a follower store. it's written by
+    /// replication only.
+    pub fn follower(store: SharedKvStore) -> Self {
+        Self { store, read_only: true, backup_root: None, metrics: Arc::default() }
+    }
+
+    /// accept backups requested by clients, each into a directory
+    /// named by the client under `root`. backups are refused without it.
+    pub fn with_backup_root<P: Into<PathBuf>>(mut self, root: P) -> Self {
+        self.backup_root = Some(root.into());
+        self
+    }
+
+    /// listen on a given address and serve forever.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        self.serve(TcpListener::bind(addr)?)
+    }
+
+    /// serve connections accepted by a given listener.
+    pub fn serve(self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            std::thread::spawn(move || {
+                // a broken connection only affects its own client.
+                let _ = server.handle(stream);
+            });
+        }
+        Ok(())
+    }
+
+    /// listen on a given address and serve metrics over http at
+    /// `/metrics` in prometheus text format forever.
+    pub fn run_metrics<A: ToSocketAddrs>(&self, addr: A) -> Result<()> {
+        self.serve_metrics(TcpListener::bind(addr)?)
+    }
+
+    /// serve metrics on connections accepted by a given listener.
+    pub fn serve_metrics(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let server = self.clone();
+            std::thread::spawn(move || {
+                let _ = metrics::handle_http(stream, &server.metrics, &server.store);
+            });
+        }
+        Ok(())
+    }
+
+    fn handle(&self, stream: TcpStream) -> Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+        loop {
+            let request = match protocol::receive(&mut reader) {
+                Ok(request) => request,
+                // client closed the connection.
+                Err(KvsError::IoError(ref err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+                // the rest of the frame isn't read, so the connection
+                // can't be used any more.
+                Err(err @ KvsError::MessageTooLarge(..)) => {
+                    protocol::send(&mut writer, &Response::Error(format!("{:?}", err)))?;
+                    return Err(err);
+                },
+                Err(err) => return Err(err),
+            };
+            // streams of replication and watching aren't counted.
+            let op = metrics::op_name(&request);
+            let started = Instant::now();
+            let response = match request {
+                Request::Get(key) => self.store.get(&key).map(Response::Value),
+                Request::Keys => Ok(Response::Keys(self.store.lock().keys())),
+                Request::Set(..) | Request::Remove(..) if self.read_only => {
+                    Ok(Response::Error("read only follower".into()))
+                },
+                Request::Set(key, value) => self.store.set(&key, &value).map(|_| Response::Done),
+                Request::Remove(key) => self.store.remove(&key).map(|_| Response::Done),
+                Request::Replicate(after_seq) => {
+                    return replica::feed(&self.store, &mut writer, after_seq);
+                },
+                Request::Watch(prefix, since) => {
+                    return self.stream_changes(&mut writer, prefix, since);
+                },
+                Request::Backup(name) => self.backup(&name).map(Response::Backup),
+                Request::Raft(..) | Request::AddNode(..) | Request::RemoveNode(..) => {
+                    Ok(Response::Error("not a raft node".into()))
+                },
+            };
+            self.metrics.observe(op, started.elapsed(), &response);
+            let response = match response {
+                Ok(response) => response,
+                Err(KvsError::NotFound) => Response::NotFound,
+                Err(err) => Response::Error(format!("{:?}", err)),
+            };
+            protocol::send(&mut writer, &response)?;
+        }
+    }
+
+    /// back up into a directory under the backup root. the name
+    /// can't be absolute or climb out of the root.
+    fn backup(&self, name: &str) -> Result<u64> {
+        let root = match &self.backup_root {
+            Some(root) => root,
+            None => return Err(KvsError::ServerError("backups aren't enabled on server".into())),
+        };
+        let name = Path::new(name);
+        if name.as_os_str().is_empty()
+            || !name.components().all(|component| matches!(component, Component::Normal(_))) {
+            return Err(KvsError::InvalidBackup(
+                format!("{} isn't a relative name under backup root", name.display())));
+        }
+        self.store.backup(root.join(name))
+    }
+
+    /// send changes until the client is disconnected, or the watcher
+    /// falls behind.
+    fn stream_changes(&self, mut writer: impl Write, prefix: Vec<u8>, since: Option<u64>) -> Result<()> {
+        // watch before the end of log is taken, so no write is missed
+        // or sent twice.
+        let (cursor, mut watcher) = {
+            let mut store = self.store.lock();
+            let watcher = store.watch(&prefix);
+            let cursor = since.map(|seq| store.change_cursor(seq)).transpose()?;
+            (cursor, watcher)
+        };
+        if let Some(mut cursor) = cursor {
+            loop {
+                // the store is unlocked between batches of log.
+                let changes = match self.store.lock().changes_since(&mut cursor) {
+                    Ok(Some(changes)) => changes,
+                    Ok(None) => break,
+                    Err(err) => {
+                        protocol::send(&mut writer, &Response::Error(format!("{:?}", err)))?;
+                        return Err(err);
+                    },
+                };
+                for change in changes.into_iter().filter(|change| change.1.starts_with(&prefix)) {
+                    protocol::send(&mut writer, &Response::Change(change))?;
+                }
+            }
+        }
+        for change in &mut watcher {
+            protocol::send(&mut writer, &Response::Change(change))?;
+        }
+        if watcher.overflowed() {
+            let message = "watcher fell behind, watch again since the last change";
+            protocol::send(&mut writer, &Response::Error(message.into()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use super::*;
+    use crate::{KvStore, KvsClient, Options};
+
+    #[test]
+    fn test_reject_oversized_message() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let store = SharedKvStore::new(KvStore::open(&tmpdir, Options::default()).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = KvsServer::new(store);
+        std::thread::spawn(move || server.serve(listener));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&(protocol::MAX_MESSAGE_BYTES + 1).to_be_bytes()).unwrap();
+        match protocol::receive(&stream).unwrap() {
+            Response::Error(message) => assert!(message.contains("MessageTooLarge"), "{}", message),
+            response => panic!("unexpected response {:?}", response),
+        }
+        // the connection is closed after it.
+        assert_eq!(stream.read(&mut [0u8; 1]).unwrap(), 0);
+
+        let mut client = KvsClient::connect(addr).unwrap();
+        client.set(b"key", b"value").unwrap();
+        assert_eq!(client.get(b"key").unwrap(), Some(b"value".to_vec()));
+    }
+}
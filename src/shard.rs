@@ -0,0 +1,470 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crate::{KvsClient, SharedKvStore};
+use crate::error::{KvsError, Result};
+
+// points of each shard on ring, so keys are spread evenly.
+const VIRTUAL_NODES: u64 = 64;
+// keys moved while writes of moving keys are blocked.
+const MOVE_BATCH: usize = 256;
+// tries of a request to a remote shard, over reconnects and redirects.
+const REMOTE_ATTEMPTS: usize = 5;
+// wait before trying again, while a leader is elected or a server
+// restarts.
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// a partition of keys, kept in process or by a server.
+pub enum Shard {
+    /// a store in this process.
+    Local(SharedKvStore),
+    /// a `KvsServer` or leader of a raft cluster.
+    Remote(RemoteShard),
+}
+
+/// connection to a remote shard. it's made again after an io error,
+/// and follows the leader of a raft cluster.
+pub struct RemoteShard {
+    inner: Mutex<RemoteConn>,
+}
+
+struct RemoteConn {
+    addrs: Vec<SocketAddr>,
+    // `None` after the connection failed.
+    client: Option<KvsClient>,
+}
+
+impl RemoteShard {
+    /// run a request on the current connection. it's sent again on a
+    /// new connection after an io error, so a write may be applied
+    /// twice, and to leader if the server isn't.
+    fn call<T, F: FnMut(&mut KvsClient) -> Result<T>>(&self, mut request: F) -> Result<T> {
+        let mut conn = self.inner.lock().unwrap();
+        let mut attempt = 1;
+        loop {
+            let result = match conn.client.as_mut() {
+                Some(client) => request(client),
+                None => KvsClient::connect(&conn.addrs[..]).and_then(|mut client| {
+                    let result = request(&mut client);
+                    conn.client = Some(client);
+                    result
+                }),
+            };
+            if attempt == REMOTE_ATTEMPTS {
+                return result;
+            }
+            attempt += 1;
+            match result {
+                Err(KvsError::IoError(..)) => {
+                    conn.client = None;
+                    std::thread::sleep(RETRY_DELAY);
+                },
+                Err(KvsError::NotLeader(Some(leader))) => {
+                    conn.addrs = leader.to_socket_addrs()?.collect();
+                    conn.client = None;
+                },
+                // no leader is known while it's elected.
+                Err(KvsError::NotLeader(None)) => std::thread::sleep(RETRY_DELAY),
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Shard {
+    /// a shard served by a remote server.
+    pub fn remote<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let addrs: Vec<_> = addr.to_socket_addrs()?.collect();
+        let client = KvsClient::connect(&addrs[..])?;
+        Ok(Shard::Remote(RemoteShard {
+            inner: Mutex::new(RemoteConn { addrs, client: Some(client) }),
+        }))
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self {
+            Shard::Local(store) => store.get(key),
+            Shard::Remote(remote) => remote.call(|client| client.get(key)),
+        }
+    }
+
+    fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self {
+            Shard::Local(store) => store.set(key, value),
+            Shard::Remote(remote) => remote.call(|client| client.set(key, value)),
+        }
+    }
+
+    fn remove(&self, key: &[u8]) -> Result<()> {
+        match self {
+            Shard::Local(store) => store.remove(key),
+            Shard::Remote(remote) => remote.call(|client| client.remove(key)),
+        }
+    }
+
+    fn keys(&self) -> Result<Vec<Vec<u8>>> {
+        match self {
+            Shard::Local(store) => Ok(store.lock().keys()),
+            Shard::Remote(remote) => remote.call(|client| client.keys()),
+        }
+    }
+}
+
+/// consistent hashing of keys to shard names.
+#[derive(Clone)]
+struct HashRing {
+    points: BTreeMap<u64, String>,
+}
+
+impl HashRing {
+    fn new<'a, I: Iterator<Item = &'a String>>(names: I) -> Self {
+        let mut points = BTreeMap::new();
+        for name in names {
+            for i in 0..VIRTUAL_NODES {
+                points.insert(hash(format!("{}#{}", name, i).as_bytes()), name.clone());
+            }
+        }
+        Self { points }
+    }
+
+    fn owner(&self, key: &[u8]) -> Result<&str> {
+        self.points.range(hash(key)..).next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, name)| name.as_str())
+            .ok_or(KvsError::NoShard)
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self.points.values().cloned().collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+/// FNV-1a with a final mix. it has to be stable, since every
+/// client routes keys the same way.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = 0xcbf29ce484222325u64;
+    for byte in bytes {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h
+}
+
+struct Routing {
+    ring: HashRing,
+    // ring before the running rebalance. keys not moved yet are
+    // still found on their previous owners.
+    previous: Option<HashRing>,
+    shards: HashMap<String, Arc<Shard>>,
+}
+
+impl Routing {
+    fn shard(&self, name: &str) -> &Shard {
+        &self.shards[name]
+    }
+
+    /// previous owner of a key if it's moving to another shard.
+    fn previous_owner(&self, key: &[u8], owner: &str) -> Result<Option<&str>> {
+        match &self.previous {
+            Some(previous) => {
+                let previous = previous.owner(key)?;
+                Ok(if previous != owner { Some(previous) } else { None })
+            },
+            None => Ok(None),
+        }
+    }
+}
+
+/// a routing client of keys partitioned across shards by
+/// consistent hashing. shards are added or removed online: keys
+/// are moved in small batches while gets and writes go on.
+pub struct ShardedStore {
+    routing: RwLock<Routing>,
+    // held by writers of keys which are moving, and by rebalance while
+    // it moves a batch, so an old value is never moved over a new one.
+    moving: RwLock<()>,
+}
+
+impl ShardedStore {
+    /// route keys across named shards.
+    pub fn new(shards: Vec<(String, Shard)>) -> Self {
+        let shards: HashMap<_, _> = shards.into_iter()
+            .map(|(name, shard)| (name, Arc::new(shard)))
+            .collect();
+        Self {
+            routing: RwLock::new(Routing {
+                ring: HashRing::new(shards.keys()),
+                previous: None,
+                shards,
+            }),
+            moving: RwLock::new(()),
+        }
+    }
+
+    /// names of shards in ring.
+    pub fn shards(&self) -> Vec<String> {
+        self.routing.read().unwrap().ring.names()
+    }
+
+    /// name of shard which owns a key.
+    pub fn owner(&self, key: &[u8]) -> Result<String> {
+        Ok(self.routing.read().unwrap().ring.owner(key)?.to_string())
+    }
+
+    /// get a binary value with a given binary key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let routing = self.routing.read().unwrap();
+        let owner = routing.ring.owner(key)?;
+        let previous = routing.previous_owner(key, owner)?;
+        // batches aren't moved between reading both shards.
+        let _moving = previous.map(|_| self.moving.read().unwrap());
+        let value = routing.shard(owner).get(key)?;
+        match (value, previous) {
+            (None, Some(previous)) => match routing.shard(previous).get(key)? {
+                // a writer may move the key meanwhile. it's set on owner
+                // before removed from previous owner, so it's on owner now.
+                None => routing.shard(owner).get(key),
+                value => Ok(value),
+            },
+            (value, _) => Ok(value),
+        }
+    }
+
+    /// set a binary key/value pairs.
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let routing = self.routing.read().unwrap();
+        let owner = routing.ring.owner(key)?;
+        let previous = routing.previous_owner(key, owner)?;
+        let _moving = previous.map(|_| self.moving.read().unwrap());
+        routing.shard(owner).set(key, value)?;
+        if let Some(previous) = previous {
+            // so the old value is never moved over the new one.
+            match routing.shard(previous).remove(key) {
+                Ok(()) | Err(KvsError::NotFound) => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// remove a binary key/value pairs by a given binary key.
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        let routing = self.routing.read().unwrap();
+        let owner = routing.ring.owner(key)?;
+        let previous = routing.previous_owner(key, owner)?;
+        let _moving = previous.map(|_| self.moving.read().unwrap());
+        let result = routing.shard(owner).remove(key);
+        match previous {
+            Some(previous) => match (result, routing.shard(previous).remove(key)) {
+                (Err(KvsError::NotFound), other) | (other, Err(KvsError::NotFound)) => other,
+                (result, other) => result.and(other),
+            },
+            None => result,
+        }
+    }
+
+    /// add a shard and move keys it owns to it.
+    /// return count of keys moved.
+    pub fn add_shard(&self, name: &str, shard: Shard) -> Result<u64> {
+        let mut moved = self.finish_rebalance()?;
+        {
+            let mut routing = self.routing.write().unwrap();
+            routing.shards.insert(name.to_string(), Arc::new(shard));
+            let ring = HashRing::new(routing.shards.keys());
+            routing.previous = Some(std::mem::replace(&mut routing.ring, ring));
+        }
+        moved += self.finish_rebalance()?;
+        Ok(moved)
+    }
+
+    /// move keys off a shard, then drop it.
+    /// return count of keys moved.
+    pub fn remove_shard(&self, name: &str) -> Result<u64> {
+        let mut moved = self.finish_rebalance()?;
+        {
+            let mut routing = self.routing.write().unwrap();
+            let names: Vec<_> = routing.shards.keys().filter(|n| *n != name).cloned().collect();
+            if names.is_empty() {
+                return Err(KvsError::NoShard);
+            }
+            let ring = HashRing::new(names.iter());
+            routing.previous = Some(std::mem::replace(&mut routing.ring, ring));
+        }
+        moved += self.finish_rebalance()?;
+        Ok(moved)
+    }
+
+    /// move keys off their previous owners. an interrupted rebalance
+    /// is finished by next one. routing is only read while keys are
+    /// moved, it's changed once they are all moved.
+    fn finish_rebalance(&self) -> Result<u64> {
+        let (ring, sources, shards) = {
+            let routing = self.routing.read().unwrap();
+            let sources: Vec<_> = match &routing.previous {
+                Some(previous) => previous.names().into_iter()
+                    .map(|name| (name.clone(), routing.shards[&name].clone()))
+                    .collect(),
+                None => return Ok(0),
+            };
+            (routing.ring.clone(), sources, routing.shards.clone())
+        };
+
+        let mut moved = 0;
+        for (name, source) in sources {
+            let mut moves = Vec::new();
+            for key in source.keys()? {
+                let owner = ring.owner(&key)?;
+                if owner != name {
+                    moves.push((key, shards[owner].clone()));
+                }
+            }
+            for batch in moves.chunks(MOVE_BATCH) {
+                // writers always remove the key from previous owner, so
+                // a key still there has no newer value on new owner.
+                // writers of moving keys wait while a batch moves.
+                let _moving = self.moving.write().unwrap();
+                for (key, owner) in batch {
+                    if let Some(value) = source.get(key)? {
+                        owner.set(key, &value)?;
+                        source.remove(key)?;
+                        moved += 1;
+                    }
+                }
+            }
+        }
+
+        let mut routing = self.routing.write().unwrap();
+        routing.previous = None;
+        let names = routing.ring.names();
+        routing.shards.retain(|name, _| names.contains(name));
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::*;
+    use crate::{KvStore, KvsServer, Options};
+    use crate::protocol::{self, Request, Response};
+
+    fn local_shard(dir: &tempfile::TempDir) -> (SharedKvStore, Shard) {
+        let store = SharedKvStore::new(KvStore::open(dir, Options::default()).unwrap());
+        (store.clone(), Shard::Local(store))
+    }
+
+    #[test]
+    fn test_rebalance_shards() {
+        let dirs: Vec<_> = (0..4).map(|_| tempfile::tempdir().unwrap()).collect();
+        let mut stores = Vec::new();
+        let mut shards = Vec::new();
+        for (i, dir) in dirs.iter().take(3).enumerate() {
+            let (store, shard) = local_shard(dir);
+            stores.push(store);
+            shards.push((format!("shard{}", i), shard));
+        }
+        let sharded = Arc::new(ShardedStore::new(shards));
+        for i in 0..600 {
+            sharded.set(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        for store in &stores {
+            assert!(store.lock().keys().len() > 100);
+        }
+
+        // writes go on while keys are moved.
+        let writer = {
+            let sharded = sharded.clone();
+            std::thread::spawn(move || {
+                for i in 0..600 {
+                    sharded.set(format!("key{}", i).as_bytes(), b"new").unwrap();
+                }
+                sharded.remove(b"key0").unwrap();
+            })
+        };
+        // and reads never miss a key being moved.
+        let names: Vec<_> = (0..4).map(|i| format!("shard{}", i)).collect();
+        let ring = HashRing::new(names.iter());
+        let moving: Arc<Vec<_>> = Arc::new((1..600).map(|i| format!("key{}", i))
+            .filter(|key| ring.owner(key.as_bytes()).unwrap() == "shard3")
+            .collect());
+        let done = Arc::new(AtomicBool::new(false));
+        let readers: Vec<_> = (0..4).map(|_| {
+            let (sharded, moving, done) = (sharded.clone(), moving.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::SeqCst) {
+                    for key in moving.iter() {
+                        assert!(sharded.get(key.as_bytes()).unwrap().is_some(), "{} is missed", key);
+                    }
+                }
+            })
+        }).collect();
+        let (store, shard) = local_shard(&dirs[3]);
+        let moved = sharded.add_shard("shard3", shard).unwrap();
+        writer.join().unwrap();
+        done.store(true, Ordering::SeqCst);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert!(moved < 300);
+        assert!(store.lock().keys().len() > 50);
+
+        stores.push(store);
+        let total: usize = stores.iter().map(|store| store.lock().keys().len()).sum();
+        assert_eq!(total, 599);
+        assert_eq!(sharded.get(b"key0").unwrap(), None);
+        for i in 1..600 {
+            let key = format!("key{}", i);
+            assert_eq!(sharded.get(key.as_bytes()).unwrap(), Some(b"new".to_vec()));
+            let owner: usize = sharded.owner(key.as_bytes()).unwrap()[5..].parse().unwrap();
+            assert_eq!(stores[owner].get(key.as_bytes()).unwrap(), Some(b"new".to_vec()));
+        }
+
+        sharded.remove_shard("shard1").unwrap();
+        assert_eq!(stores[1].lock().keys().len(), 0);
+        assert_eq!(sharded.shards(), vec!["shard0", "shard2", "shard3"]);
+        assert_eq!(sharded.get(b"key1").unwrap(), Some(b"new".to_vec()));
+        assert!(matches!(sharded.remove(b"key0"), Err(KvsError::NotFound)));
+    }
+
+    #[test]
+    fn test_remote_reconnect_and_redirect() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let (store, _) = local_shard(&tmpdir);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = KvsServer::new(store.clone());
+        std::thread::spawn(move || {
+            // the first connection fails, later ones are served.
+            std::mem::drop(listener.accept().unwrap());
+            server.serve(listener)
+        });
+
+        // a follower which only knows the leader.
+        let follower = TcpListener::bind("127.0.0.1:0").unwrap();
+        let follower_addr = follower.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = follower.accept().unwrap();
+            let _: Request = protocol::receive(BufReader::new(&stream)).unwrap();
+            protocol::send(&stream, &Response::NotLeader(Some(addr.to_string()))).unwrap();
+        });
+
+        let shard = Shard::remote(addr).unwrap();
+        shard.set(b"key1", b"value1").unwrap();
+        assert_eq!(store.get(b"key1").unwrap(), Some(b"value1".to_vec()));
+
+        let shard = Shard::remote(follower_addr).unwrap();
+        shard.set(b"key2", b"value2").unwrap();
+        assert_eq!(shard.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+        assert_eq!(store.get(b"key2").unwrap(), Some(b"value2".to_vec()));
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::{KvStore, SyncPolicy};
+use crate::batch::BatchOp;
+use crate::error::{KvsError, Result};
+
+/// a KvStore shared by threads.
+///
+/// writes from concurrent callers are committed in groups: the
+/// first writer becomes leader, takes every queued write, appends
+/// them with one write and one sync, then wakes up all waiters.
+///
+/// with `SyncPolicy::Interval`, a background thread syncs writes left
+/// unsynced once the interval passes, so they don't wait for more
+/// writes.
+#[derive(Clone)]
+pub struct SharedKvStore {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    store: Arc<Mutex<KvStore>>,
+    queue: Mutex<CommitQueue>,
+    committed: Condvar,
+    flusher: Option<Flusher>,
+}
+
+/// the thread syncing a store by interval. it's stopped and joined
+/// when the store is dropped, so the store is closed by then.
+struct Flusher {
+    stop: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Some(Flusher { stop, thread }) = self.flusher.take() {
+            std::mem::drop(stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default)]
+struct CommitQueue {
+    next_ticket: u64,
+    pending: Vec<(u64, BatchOp)>,
+    committing: bool,
+    results: HashMap<u64, Result<()>>,
+}
+
+impl SharedKvStore {
+    /// share a store between threads.
+    pub fn new(store: KvStore) -> Self {
+        let interval = match store.options.sync_policy {
+            SyncPolicy::Interval(interval) => Some(interval),
+            _ => None,
+        };
+        let store = Arc::new(Mutex::new(store));
+        let flusher = interval.map(|interval| spawn_flusher(store.clone(), interval));
+        Self {
+            inner: Arc::new(Inner {
+                store,
+                queue: Mutex::new(CommitQueue::default()),
+                committed: Condvar::new(),
+                flusher,
+            })
+        }
+    }
+
+    /// get a binary value with a given binary key.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.try_lock()?.get_bytes(key)
+    }
+
+    /// set a binary key/value pairs.
+    pub fn set(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.commit(BatchOp::Set(key.to_vec(), value.to_vec()))
+    }
+
+    /// remove a binary key/value pairs by a given binary key.
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        self.commit(BatchOp::Remove(key.to_vec()))
+    }
+
+    /// lock the underlying store for other operations. it panics if
+    /// another thread panicked while holding it.
+    pub fn lock(&self) -> MutexGuard<'_, KvStore> {
+        self.inner.store.lock().unwrap()
+    }
+
+    fn try_lock(&self) -> Result<MutexGuard<'_, KvStore>> {
+        self.inner.store.lock().map_err(|_| KvsError::Poisoned)
+    }
+
+    fn commit(&self, op: BatchOp) -> Result<()> {
+        let mut queue = self.inner.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.pending.push((ticket, op));
+
+        loop {
+            if let Some(result) = queue.results.remove(&ticket) {
+                return result;
+            }
+            if queue.committing {
+                queue = self.inner.committed.wait(queue).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+
+            // become leader of the queued writes.
+            queue.committing = true;
+            let (tickets, ops): (Vec<_>, Vec<_>) = queue.pending.drain(..).unzip();
+            std::mem::drop(queue);
+
+            let mut leader = Leader { inner: &self.inner, tickets, results: None };
+            let results = match self.try_lock().and_then(|mut store| store.commit_batch(ops)) {
+                Ok(results) => results,
+                Err(err) => {
+                    let err = Arc::new(err);
+                    leader.tickets.iter().map(|_| Err(KvsError::Shared(err.clone()))).collect()
+                },
+            };
+            leader.results = Some(results);
+            std::mem::drop(leader);
+            queue = self.inner.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    #[cfg(test)]
+    fn pending(&self) -> usize {
+        self.inner.queue.lock().unwrap().pending.len()
+    }
+}
+
+/// sync writes of a store once they are unsynced for an interval.
+fn spawn_flusher(store: Arc<Mutex<KvStore>>, interval: Duration) -> Flusher {
+    let (stop, stopped) = mpsc::channel();
+    let thread = std::thread::spawn(move || {
+        let mut wait = interval;
+        loop {
+            match stopped.recv_timeout(wait) {
+                Err(RecvTimeoutError::Timeout) => {},
+                _ => return,
+            }
+            let mut store = match store.lock() {
+                Ok(store) => store,
+                Err(_) => return,
+            };
+            wait = interval;
+            if store.writes_since_sync > 0 {
+                let elapsed = store.last_sync.elapsed();
+                if elapsed >= interval {
+                    // a failed sync is tried again, and reported by
+                    // the next write.
+                    let _ = store.sync();
+                } else {
+                    wait = interval - elapsed;
+                }
+            }
+            // a zero interval doesn't spin.
+            wait = std::cmp::max(wait, Duration::from_millis(1));
+        }
+    });
+    Flusher { stop, thread }
+}
+
+/// hands results of a group to its writers and lets the next leader
+/// go on when dropped, even if committing panics.
+struct Leader<'a> {
+    inner: &'a Inner,
+    tickets: Vec<u64>,
+    results: Option<Vec<Result<()>>>,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        let mut queue = self.inner.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let tickets = std::mem::take(&mut self.tickets);
+        match self.results.take() {
+            Some(results) => queue.results.extend(tickets.into_iter().zip(results)),
+            None => queue.results.extend(tickets.into_iter().map(|ticket| (ticket, Err(KvsError::Poisoned)))),
+        }
+        queue.committing = false;
+        self.inner.committed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    #[test]
+    fn test_concurrent_writers() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { sync_policy: SyncPolicy::Always, ..Options::default() };
+        let store = SharedKvStore::new(KvStore::open(&tmpdir, options).unwrap());
+        let handles: Vec<_> = (0..8).map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for i in 0..50 {
+                    let key = format!("key{}-{}", t, i);
+                    store.set(key.as_bytes(), b"value").unwrap();
+                }
+                store.remove(format!("key{}-0", t).as_bytes()).unwrap();
+                assert!(store.remove(b"missing").is_err());
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        std::mem::drop(store);
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        for t in 0..8 {
+            assert_eq!(kvs.get(format!("key{}-0", t)).unwrap(), None);
+            for i in 1..50 {
+                assert_eq!(kvs.get(format!("key{}-{}", t, i)).unwrap(), Some("value".into()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_writes_committed_in_group() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let store = SharedKvStore::new(KvStore::new_from(&tmpdir).unwrap());
+
+        // hold the store, so the first writer waits with its own write
+        // and all others are queued for the next group.
+        let guard = store.lock();
+        let handles: Vec<_> = (0..10).map(|i| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                store.set(format!("key{}", i).as_bytes(), b"value").unwrap();
+            })
+        }).collect();
+        while store.pending() < 9 {
+            std::thread::yield_now();
+        }
+        std::mem::drop(guard);
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        assert_eq!(store.lock().stats().unwrap().batches, 2);
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i).as_bytes()).unwrap(), Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_writers_after_panic() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let store = SharedKvStore::new(KvStore::new_from(&tmpdir).unwrap());
+
+        // a leader panics while committing, its writers get an error.
+        store.inner.queue.lock().unwrap().committing = true;
+        store.inner.queue.lock().unwrap().next_ticket = 1;
+        let inner = store.clone();
+        std::thread::spawn(move || {
+            let _leader = Leader { inner: &inner.inner, tickets: vec![0], results: None };
+            panic!("commit failed");
+        }).join().unwrap_err();
+        {
+            let mut queue = store.inner.queue.lock().unwrap();
+            assert!(!queue.committing);
+            assert!(matches!(queue.results.remove(&0), Some(Err(KvsError::Poisoned))));
+        }
+
+        // the store is poisoned while writers wait for it.
+        let holder = store.clone();
+        let (locked, waiting) = std::sync::mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let _guard = holder.lock();
+            locked.send(()).unwrap();
+            while holder.pending() == 0 && !holder.inner.queue.lock().unwrap().committing {
+                std::thread::yield_now();
+            }
+            panic!("poison the store");
+        });
+        waiting.recv().unwrap();
+        match store.set(b"key", b"value") {
+            Err(KvsError::Shared(err)) => assert!(matches!(*err, KvsError::Poisoned)),
+            other => panic!("unexpected result {:?}", other),
+        }
+        handle.join().unwrap_err();
+        assert!(matches!(store.get(b"key"), Err(KvsError::Poisoned)));
+        assert!(store.remove(b"key").is_err());
+    }
+
+    #[test]
+    fn test_sync_by_interval() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            sync_policy: SyncPolicy::Interval(Duration::from_millis(20)),
+            ..Options::default()
+        };
+        let store = SharedKvStore::new(KvStore::open(&tmpdir, options).unwrap());
+        store.set(b"key1", b"value1").unwrap();
+        store.set(b"key2", b"value2").unwrap();
+
+        // no more writes come, the flusher syncs them.
+        for _ in 0..100 {
+            if store.lock().writes_since_sync == 0 {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(store.lock().writes_since_sync, 0);
+
+        // the store is closed once it's dropped.
+        std::mem::drop(store);
+        KvStore::open(&tmpdir, Options::default()).unwrap();
+    }
+}
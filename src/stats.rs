@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+use crate::{KvStore, OnDiskCommand, OnDiskMeta, OnDiskPointer, OnDiskValue, Value};
+use crate::error::Result;
+
+/// statistics of a KvStore. counters of writes are
+/// since it's opened.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    /// count of values written into cmd.wal.
+    pub values_written: u64,
+    /// count of values which are stored compressed.
+    pub compressed_values: u64,
+    /// bytes of values before compression.
+    pub value_bytes: u64,
+    /// bytes of values actually stored in cmd.wal.
+    pub stored_value_bytes: u64,
+    /// count of write batches, each costs one write and
+    /// at most one sync of logs.
+    pub batches: u64,
+    /// count of values kept inline in index.
+    pub inline_values: u64,
+    /// bytes of values kept inline in index.
+    pub inline_bytes: u64,
+    /// reads served by cache.
+    pub cache_hits: u64,
+    /// reads missed cache and went to cmd.wal.
+    pub cache_misses: u64,
+    /// bytes of keys and values in cache.
+    pub cache_bytes: u64,
+    /// reads of cmd.wal served by memory map.
+    pub mapped_reads: u64,
+    /// bytes appended to cmd.wal by writes.
+    pub cmd_bytes_written: u64,
+    /// count of live keys.
+    pub live_keys: u64,
+    /// sequence of the latest write.
+    pub latest_seq: u64,
+    /// size of cmd.wal.
+    pub cmd_bytes: u64,
+    /// bytes of cmd.wal taken by overwritten or removed values,
+    /// which compaction reclaims.
+    pub cmd_stale_bytes: u64,
+    /// size of meta.wal.
+    pub meta_bytes: u64,
+    /// bytes of meta.wal taken by outdated index entries.
+    pub meta_stale_bytes: u64,
+    /// estimated bytes of memory taken by index.
+    pub index_bytes: u64,
+    /// count of compactions since opened.
+    pub compactions: u64,
+    /// when the last compaction since opened finished, in
+    /// seconds since unix epoch.
+    pub last_compaction: Option<u64>,
+    /// bytes reclaimed by the last compaction.
+    pub last_compaction_reclaimed: u64,
+}
+
+impl Stats {
+    /// ratio of stored bytes to original bytes of values.
+    /// 1.0 means nothing saved.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.value_bytes == 0 {
+            1.0
+        } else {
+            self.stored_value_bytes as f64 / self.value_bytes as f64
+        }
+    }
+}
+
+impl KvStore {
+    /// statistics of this store. sizes of live records are read from
+    /// cmd.wal and meta.wal is scanned to tell stale bytes, so it
+    /// costs a small read per key.
+    pub fn stats(&self) -> Result<Stats> {
+        let mut stats = self.stats.clone();
+        stats.cache_bytes = self.cache.as_ref().map_or(0, |cache| cache.size() as u64);
+        stats.live_keys = self.location_finder.len() as u64;
+        stats.latest_seq = self.latest_seq;
+        stats.index_bytes = self.index_bytes();
+        stats.compactions = self.compactions;
+        if let Some((time, reclaimed)) = self.last_compaction {
+            stats.last_compaction = time.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs());
+            stats.last_compaction_reclaimed = reclaimed;
+        }
+
+        stats.cmd_bytes = self.wal_cmd.fd.metadata()?.len();
+        let mut live_bytes = 0;
+        for value in self.location_finder.values() {
+            if let Value::Location(offset) | Value::Content(offset, ..) = value {
+                live_bytes += self.cmd_record_size(*offset)?;
+            }
+        }
+        stats.cmd_stale_bytes = stats.cmd_bytes.saturating_sub(live_bytes);
+
+        stats.meta_bytes = self.wal_meta_writer.get_ref().metadata()?.len();
+        stats.meta_stale_bytes = stats.meta_bytes.saturating_sub(self.live_meta_bytes(stats.meta_bytes)?);
+        Ok(stats)
+    }
+
+    /// keys, values kept inline and slots of hash table.
+    fn index_bytes(&self) -> u64 {
+        let slot = std::mem::size_of::<Vec<u8>>() + std::mem::size_of::<Value>() + 1;
+        let keys: usize = self.location_finder.keys().map(|key| key.len()).sum();
+        (self.location_finder.capacity() * slot + keys) as u64 + self.stats.inline_bytes
+    }
+
+    /// size of a record in cmd.wal by its length prefix.
+    fn cmd_record_size(&self, offset: u64) -> Result<u64> {
+        let mut len_bytes = [0u8; 4];
+        match self.cmd_map.as_ref() {
+            Some(map) if offset + 4 <= map.len() as u64 => {
+                len_bytes.copy_from_slice(&map[offset as usize..offset as usize + 4]);
+            },
+            _ => {
+                // every reader of cmd.wal seeks before it reads.
+                let mut fd = &self.wal_cmd.fd;
+                fd.seek(SeekFrom::Start(offset))?;
+                fd.read_exact(&mut len_bytes)?;
+            },
+        }
+        Ok(4 + u32::from_be_bytes(len_bytes) as u64)
+    }
+
+    /// bytes of the latest index entry of each live key in meta.wal.
+    fn live_meta_bytes(&self, meta_bytes: u64) -> Result<u64> {
+        let mut reader = BufReader::new(&self.wal_meta.fd);
+        let mut iter = self.wal_meta.iter(&mut reader);
+        // size and pointer of the latest entry of each key.
+        let mut latest: HashMap<Vec<u8>, (u64, Option<u64>)> = HashMap::new();
+        // a record ends where the next one starts.
+        let mut previous: Option<(u64, Vec<u8>, Option<u64>)> = None;
+        for (offset, meta) in &mut iter {
+            if let Some((start, key, target)) = previous.take() {
+                latest.insert(key, (offset - start, target));
+            }
+            if let OnDiskMeta::CmdIndex(OnDiskCommand{key, value}) = meta {
+                let target = match value {
+                    OnDiskValue::Pointer(_, OnDiskPointer{offset, ..}) => Some(offset),
+                    _ => None,
+                };
+                previous = Some((offset, key, target));
+            }
+        }
+        iter.finish()?;
+        if let Some((start, key, target)) = previous {
+            latest.insert(key, (meta_bytes - start, target));
+        }
+
+        // an entry is live if the key still points at its record.
+        Ok(latest.into_iter().filter(|(key, (_, target))| {
+            match (self.location_finder.get(key), target) {
+                (Some(Value::Location(offset)), Some(target))
+                    | (Some(Value::Content(offset, ..)), Some(target)) => offset == target,
+                _ => false,
+            }
+        }).map(|(_, (size, _))| size).sum())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Options;
+
+    #[test]
+    fn test_stats_of_logs() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        for i in 0..100 {
+            kvs.set_bytes(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        let stats = kvs.stats().unwrap();
+        assert_eq!(stats.live_keys, 100);
+        assert_eq!(stats.latest_seq, 100);
+        assert_eq!(stats.cmd_stale_bytes, 0);
+        assert_eq!(stats.meta_stale_bytes, 0);
+        assert!(stats.index_bytes > 0);
+        assert_eq!(stats.last_compaction, None);
+
+        for i in 0..50 {
+            kvs.set_bytes(format!("key{}", i).as_bytes(), b"new value").unwrap();
+        }
+        kvs.remove_bytes(b"key99").unwrap();
+        let stats = kvs.stats().unwrap();
+        assert_eq!(stats.live_keys, 99);
+        assert!(stats.cmd_stale_bytes > 0);
+        assert!(stats.meta_stale_bytes > 0);
+
+        let reclaimed = kvs.compact().unwrap();
+        let compacted = kvs.stats().unwrap();
+        assert_eq!(compacted.live_keys, 99);
+        assert_eq!(compacted.cmd_stale_bytes, 0);
+        assert_eq!(compacted.compactions, 1);
+        assert!(compacted.last_compaction.is_some());
+        assert_eq!(compacted.last_compaction_reclaimed, reclaimed);
+        assert!(compacted.cmd_bytes < stats.cmd_bytes);
+    }
+}
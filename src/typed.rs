@@ -0,0 +1,137 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::KvStore;
+use crate::error::Result;
+
+/// encoding used to turn a typed value into the bytes kept in store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// human readable, other tools are able to decode the value.
+    #[default]
+    Json,
+    /// compact binary encoding.
+    Bincode,
+}
+
+impl Encoding {
+    fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+            Encoding::Bincode => Ok(bincode::serialize(value)?),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, bytes: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(bytes)?),
+            Encoding::Bincode => Ok(bincode::deserialize(bytes)?),
+        }
+    }
+}
+
+/// a typed view on a KvStore. values are serialized with
+/// the chosen encoding before written into store.
+pub struct TypedStore<'a> {
+    store: &'a mut KvStore,
+    encoding: Encoding,
+}
+
+impl<'a> TypedStore<'a> {
+    /// create a typed view of a given store.
+    pub fn new(store: &'a mut KvStore, encoding: Encoding) -> Self {
+        Self { store, encoding }
+    }
+
+    /// the encoding of this view.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// get a value and decode it as T.
+    pub fn get<T, K>(&mut self, key: K) -> Result<Option<T>>
+    where T: DeserializeOwned, K: AsRef<[u8]> {
+        match self.store.get_bytes(key.as_ref())? {
+            Some(bytes) => Ok(Some(self.encoding.decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// encode a value and set it with a given key.
+    pub fn set<T, K>(&mut self, key: K, value: &T) -> Result<()>
+    where T: Serialize, K: AsRef<[u8]> {
+        let bytes = self.encoding.encode(value)?;
+        self.store.set_bytes(key.as_ref(), &bytes)
+    }
+
+    /// remove a key from store.
+    pub fn remove<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+        self.store.remove_bytes(key.as_ref())
+    }
+}
+
+impl KvStore {
+    /// get a typed view of this store with a given encoding.
+    pub fn typed(&mut self, encoding: Encoding) -> TypedStore<'_> {
+        TypedStore::new(self, encoding)
+    }
+
+    /// get a value and decode it as T with default encoding.
+    pub fn get_as<T, K>(&mut self, key: K) -> Result<Option<T>>
+    where T: DeserializeOwned, K: AsRef<[u8]> {
+        self.typed(Encoding::default()).get(key)
+    }
+
+    /// encode a value with default encoding and set it.
+    pub fn set_as<T, K>(&mut self, key: K, value: &T) -> Result<()>
+    where T: Serialize, K: AsRef<[u8]> {
+        self.typed(Encoding::default()).set(key, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use super::*;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct User {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    fn user() -> User {
+        User {
+            name: "darren".into(),
+            age: 30,
+            tags: vec!["admin".into(), "ops".into()],
+        }
+    }
+
+    #[test]
+    fn test_typed_roundtrip_and_recover() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set_as("json", &user()).unwrap();
+        kvs.typed(Encoding::Bincode).set("bincode", &user()).unwrap();
+        std::mem::drop(kvs);
+
+        let mut kvs = KvStore::from_wal(&tmpdir).unwrap();
+        assert_eq!(kvs.get_as::<User, _>("json").unwrap(), Some(user()));
+        assert_eq!(kvs.get("json".into()).unwrap(),
+                   Some(serde_json::to_string(&user()).unwrap()));
+
+        let mut typed = kvs.typed(Encoding::Bincode);
+        assert_eq!(typed.get::<User, _>("bincode").unwrap(), Some(user()));
+        typed.remove("bincode").unwrap();
+        assert_eq!(typed.get::<User, _>("bincode").unwrap(), None);
+    }
+
+    #[test]
+    fn test_typed_decode_with_wrong_type() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::new_from(&tmpdir).unwrap();
+        kvs.set_as("key1", &"not a user").unwrap();
+        assert!(kvs.get_as::<User, _>("key1").is_err());
+    }
+}
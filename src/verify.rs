@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::{HintBlock, KvStore, OnDiskCommand, OnDiskMeta, OnDiskPointer, OnDiskValue, Options, Value};
+use crate::crypto::Cipher;
+use crate::error::{KvsError, Result};
+use crate::hint;
+use crate::lock;
+use crate::wal::{WalEntry, WalLog};
+
+const META_REBUILD_FILE: &str = "meta.wal.rebuild";
+// bytes of cmd.wal dropped by repair.
+const CMD_CORRUPT_FILE: &str = "cmd.wal.corrupt";
+// a longer broken tail is more likely a wrong key or damage in the
+// middle of cmd.wal than a torn write, so repair refuses to drop it.
+const MAX_DROPPED_BYTES: u64 = 1 << 20;
+
+/// result of checking a store on disk by `KvStore::verify`.
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// complete records in cmd.wal.
+    pub cmd_records: u64,
+    /// complete records in meta.wal.
+    pub meta_records: u64,
+    /// highest sequence found in logs.
+    pub latest_seq: u64,
+    /// what's wrong, with the log and offset of each one.
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    /// whether no problem is found.
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// key and sequence of a record in cmd.wal.
+struct CmdRecord {
+    key: Vec<u8>,
+    sequence: u64,
+}
+
+/// where reading a log stopped: the end of last complete record,
+/// and the error of the first record which can't be decoded.
+pub(crate) struct Scan {
+    records: u64,
+    end: u64,
+    len: u64,
+    error: Option<String>,
+}
+
+impl Scan {
+    /// report a broken or partial written tail.
+    pub(crate) fn check_tail(&self, name: &str, problems: &mut Vec<String>) {
+        match &self.error {
+            Some(err) => problems.push(format!("{}: broken record at offset {}: {}", name, self.end, err)),
+            None if self.end < self.len => problems.push(format!(
+                "{}: {} bytes of partial written record at offset {}", name, self.len - self.end, self.end)),
+            None => {},
+        }
+    }
+}
+
+/// read records of a log in order, until the first one which
+/// can't be decoded.
+pub(crate) fn scan<T, F>(path: &Path, cipher: Option<Cipher>, mut f: F) -> Result<Scan>
+where T: WalEntry, F: FnMut(u64, T) {
+    let fd = File::open(path)?;
+    let len = fd.metadata()?.len();
+    let wal = WalLog::<T>::with_cipher(fd, cipher);
+    let mut reader = BufReader::new(&wal.fd);
+    let mut iter = wal.iter(&mut reader);
+    let mut records = 0;
+    let mut last = None;
+    for (offset, record) in &mut iter {
+        f(offset, record);
+        records += 1;
+        last = Some(offset);
+    }
+    let error = iter.finish().err().map(|err| format!("{:?}", err));
+
+    // end of the last record, by its length prefix.
+    let end = match last {
+        Some(offset) => {
+            let mut reader = &wal.fd;
+            reader.seek(SeekFrom::Start(offset))?;
+            let mut len_bytes = [0u8; 4];
+            reader.read_exact(&mut len_bytes)?;
+            offset + 4 + u32::from_be_bytes(len_bytes) as u64
+        },
+        None => 0,
+    };
+    Ok(Scan { records, end, len, error })
+}
+
+impl KvStore {
+    /// check logs of a store which isn't opened. every record is
+    /// decoded, which also checks the checksum of a plain record or
+    /// the authentication tag of a sealed one, and every index entry
+    /// has to point at a record of the same key and sequence in
+    /// cmd.wal. sequences of a key have to increase along each log.
+    /// errors are returned only if logs can't be read at all.
+    pub fn verify<P: AsRef<Path>>(path: P, options: &Options) -> Result<VerifyReport> {
+        let path = path.as_ref();
+        let _lock = lock::lock_dir(path)?;
+        let cipher = options.encryption_key.as_ref().map(Cipher::new);
+        let mut report = VerifyReport::default();
+
+        let mut problems = Vec::new();
+        let mut latest_seq = 0;
+        let mut cmd_index = HashMap::new();
+        let mut last_seqs: HashMap<Vec<u8>, u64> = HashMap::new();
+        let cmd = scan(&path.join("cmd.wal"), cipher.clone(), |offset, OnDiskCommand{key, value}| {
+            let sequence = value.sequence();
+            match value.into_plain() {
+                Ok(OnDiskValue::Pointer(..)) => {
+                    problems.push(format!("cmd.wal: pointer at offset {}", offset));
+                },
+                Ok(_) => {},
+                Err(err) => problems.push(format!(
+                    "cmd.wal: broken compressed value at offset {}: {:?}", offset, err)),
+            }
+            check_sequence(&mut last_seqs, &key, sequence, "cmd.wal", offset, &mut problems);
+            latest_seq = std::cmp::max(latest_seq, sequence);
+            cmd_index.insert(offset, CmdRecord { key, sequence });
+        })?;
+        cmd.check_tail("cmd.wal", &mut problems);
+        report.cmd_records = cmd.records;
+
+        let meta_path = path.join("meta.wal");
+        if !meta_path.exists() {
+            problems.push("meta.wal: missing".to_string());
+            report.cmd_records = cmd.records;
+            report.problems = problems;
+            report.latest_seq = latest_seq;
+            return Ok(report);
+        }
+        let mut last_seqs = HashMap::new();
+        let meta = scan(&meta_path, cipher.clone(), |offset, record| {
+            let OnDiskCommand{key, value} = match record {
+                OnDiskMeta::CmdIndex(cmd) => cmd,
+                OnDiskMeta::Compaction(..) => return,
+            };
+            let sequence = value.sequence();
+            check_sequence(&mut last_seqs, &key, sequence, "meta.wal", offset, &mut problems);
+            latest_seq = std::cmp::max(latest_seq, sequence);
+            match value {
+                OnDiskValue::Pointer(_, OnDiskPointer{offset: target, ..}) => {
+                    let location = format!("meta.wal: pointer at offset {}", offset);
+                    check_pointer(&cmd_index, &key, Some(sequence), target, &location, &mut problems);
+                },
+                OnDiskValue::DeletedKey(..) => {},
+                _ => problems.push(format!("meta.wal: value instead of pointer at offset {}", offset)),
+            }
+        })?;
+        meta.check_tail("meta.wal", &mut problems);
+        report.meta_records = meta.records;
+        report.problems = problems;
+        report.latest_seq = latest_seq;
+
+        // a hint is used as it is if it matches size of logs.
+        if let Some((checkpoint, blocks)) = hint::read_hint(path, cipher) {
+            if checkpoint.cmd_offset <= cmd.len && checkpoint.meta_offset <= meta.len {
+                for HintBlock{key, value} in blocks {
+                    if let Value::Location(target) | Value::Content(target, ..) = value {
+                        check_pointer(&cmd_index, &key, None, target, "hint.wal", &mut report.problems);
+                    }
+                }
+                report.latest_seq = std::cmp::max(report.latest_seq, checkpoint.latest_seq);
+            }
+        }
+        Ok(report)
+    }
+
+    /// rebuild index of a store which isn't opened from cmd.wal alone,
+    /// then open it. meta.wal may be lost or broken. a partial written
+    /// or broken tail of cmd.wal up to 1MiB is dropped and kept in
+    /// cmd.wal.corrupt, or cmd.wal.corrupt.N if it exists. nothing is
+    /// dropped if no record can be decoded, as with a wrong key.
+    pub fn repair<P: AsRef<Path>>(p: P, options: Options) -> Result<Self> {
+        let path = p.as_ref();
+        let lock = lock::lock_dir(path)?;
+        Self::finish_compaction(path)?;
+        let cipher = options.encryption_key.as_ref().map(Cipher::new);
+        let cmd_path = path.join("cmd.wal");
+        let cmd = scan::<OnDiskCommand, _>(&cmd_path, cipher, |_, _| {})?;
+        if cmd.end < cmd.len {
+            let dropped = cmd.len - cmd.end;
+            if cmd.records == 0 {
+                return Err(KvsError::UnsafeRepair(format!(
+                    "no record of cmd.wal can be decoded, {} bytes would be dropped. \
+                     is the encryption key right?", dropped)));
+            }
+            if dropped > MAX_DROPPED_BYTES {
+                return Err(KvsError::UnsafeRepair(format!(
+                    "{} bytes after offset {} of cmd.wal would be dropped", dropped, cmd.end)));
+            }
+            let mut fd = OpenOptions::new().read(true).write(true).open(&cmd_path)?;
+            fd.seek(SeekFrom::Start(cmd.end))?;
+            let mut corrupt = create_corrupt_file(path)?;
+            std::io::copy(&mut fd, &mut corrupt)?;
+            corrupt.sync_all()?;
+            fd.set_len(cmd.end)?;
+            fd.sync_all()?;
+        }
+        Self::write_meta(path, &options)?;
+        Self::open_locked(path, options, lock)
+    }
+
+    /// write a new meta.wal which indexes every record of cmd.wal,
+    /// and drop the hint. cmd.wal has to be readable to its end.
+    /// return count of records indexed.
+    pub fn rebuild_meta<P: AsRef<Path>>(path: P, options: &Options) -> Result<u64> {
+        let _lock = lock::lock_dir(path.as_ref())?;
+        Self::write_meta(path.as_ref(), options)
+    }
+
+    fn write_meta(path: &Path, options: &Options) -> Result<u64> {
+        let cipher = options.encryption_key.as_ref().map(Cipher::new);
+        let cmd = WalLog::<OnDiskCommand>::with_cipher(File::open(path.join("cmd.wal"))?, cipher.clone());
+        let meta = WalLog::<OnDiskMeta>::with_cipher(
+            OpenOptions::new().read(true).write(true).create(true).truncate(true)
+                .open(path.join(META_REBUILD_FILE))?,
+            cipher);
+
+        let mut reader = BufReader::new(&cmd.fd);
+        let mut writer = BufWriter::new(&meta.fd);
+        let mut iter = cmd.iter(&mut reader);
+        let mut records = 0;
+        for (offset, OnDiskCommand{key, value}) in &mut iter {
+            let value = match value {
+                OnDiskValue::DeletedKey(sequence) => OnDiskValue::DeletedKey(sequence),
+                value => OnDiskValue::Pointer(value.sequence(), OnDiskPointer{ fid: 0, offset }),
+            };
+            meta.append(&mut writer, &OnDiskMeta::CmdIndex(OnDiskCommand{ key, value }))?;
+            records += 1;
+        }
+        iter.finish()?;
+        writer.flush()?;
+        std::mem::drop(writer);
+        meta.fd.sync_all()?;
+
+        // the hint may not match the new meta.wal.
+        hint::remove_hint(path)?;
+        std::fs::rename(path.join(META_REBUILD_FILE), path.join("meta.wal"))?;
+        File::open(path)?.sync_all()?;
+        Ok(records)
+    }
+}
+
+/// a new file for a dropped tail, tails dropped before are kept.
+fn create_corrupt_file(path: &Path) -> Result<File> {
+    let mut name = CMD_CORRUPT_FILE.to_string();
+    for n in 1.. {
+        match OpenOptions::new().write(true).create_new(true).open(path.join(&name)) {
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                name = format!("{}.{}", CMD_CORRUPT_FILE, n);
+            },
+            result => return Ok(result?),
+        }
+    }
+    unreachable!()
+}
+
+fn check_sequence(last_seqs: &mut HashMap<Vec<u8>, u64>, key: &[u8], sequence: u64,
+                  name: &str, offset: u64, problems: &mut Vec<String>) {
+    // records sent again to a follower keep their sequences.
+    match last_seqs.get_mut(key) {
+        Some(last) if *last > sequence => problems.push(format!(
+            "{}: sequence {} at offset {} is behind {} of the same key", name, sequence, offset, last)),
+        Some(last) => *last = sequence,
+        None => {
+            last_seqs.insert(key.to_vec(), sequence);
+        },
+    }
+}
+
+fn check_pointer(cmd_index: &HashMap<u64, CmdRecord>, key: &[u8], sequence: Option<u64>,
+                 target: u64, location: &str, problems: &mut Vec<String>) {
+    match cmd_index.get(&target) {
+        None => problems.push(format!("{}: no record at offset {} of cmd.wal", location, target)),
+        Some(record) if record.key != key => {
+            problems.push(format!("{}: record at offset {} of cmd.wal has another key", location, target));
+        },
+        Some(record) if sequence.is_some_and(|sequence| sequence != record.sequence) => {
+            problems.push(format!("{}: record at offset {} of cmd.wal has sequence {}",
+                                  location, target, record.sequence));
+        },
+        Some(_) => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_and_rebuild_meta() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        for i in 0..100 {
+            kvs.set_bytes(format!("key{}", i).as_bytes(), b"value").unwrap();
+        }
+        kvs.remove_bytes(b"key0").unwrap();
+        kvs.close().unwrap();
+
+        let report = KvStore::verify(&tmpdir, &Options::default()).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!((report.cmd_records, report.meta_records, report.latest_seq), (101, 101, 101));
+
+        // meta.wal with a broken record and a pointer to nowhere.
+        let meta_path = tmpdir.path().join("meta.wal");
+        let mut data = std::fs::read(&meta_path).unwrap();
+        let mut record = Vec::new();
+        crate::wal::write_wal_entry(&mut record, OnDiskMeta::CmdIndex(OnDiskCommand {
+            key: b"key1".to_vec(),
+            value: OnDiskValue::Pointer(200, OnDiskPointer{ fid: 0, offset: 1 }),
+        }), None).unwrap();
+        data.extend(record);
+        data.extend(&[0, 0, 0, 2, 0xff, 0xff]);
+        std::fs::write(&meta_path, data).unwrap();
+
+        let report = KvStore::verify(&tmpdir, &Options::default()).unwrap();
+        assert_eq!(report.problems.len(), 2, "{:?}", report.problems);
+        assert!(report.problems.iter().any(|problem| problem.starts_with("meta.wal: broken record")));
+        assert!(report.problems.iter().any(|problem| problem.contains("no record at offset 1")));
+        assert!(KvStore::open(&tmpdir, Options::default()).is_err());
+
+        assert_eq!(KvStore::rebuild_meta(&tmpdir, &Options::default()).unwrap(), 101);
+        assert!(KvStore::verify(&tmpdir, &Options::default()).unwrap().is_ok());
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        assert_eq!(kvs.keys().len(), 99);
+        assert_eq!(kvs.get_bytes(b"key1").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(kvs.get_bytes(b"key0").unwrap(), None);
+    }
+
+    #[test]
+    fn test_repair_from_cmd_wal() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { compress_threshold: Some(16), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options.clone()).unwrap();
+        for i in 0..50 {
+            kvs.set_bytes(format!("key{}", i).as_bytes(), &[b'v'; 64]).unwrap();
+        }
+        kvs.remove_bytes(b"key0").unwrap();
+        kvs.set_bytes(b"key1", b"new").unwrap();
+        kvs.close().unwrap();
+
+        // meta.wal is lost and cmd.wal has a broken tail.
+        std::fs::remove_file(tmpdir.path().join("meta.wal")).unwrap();
+        let cmd = OpenOptions::new().append(true).open(tmpdir.path().join("cmd.wal")).unwrap();
+        (&cmd).write_all(&[0, 0, 0, 2, 0xff, 0xff]).unwrap();
+        assert!(!KvStore::verify(&tmpdir, &options).unwrap().is_ok());
+        assert!(KvStore::open(&tmpdir, options.clone()).is_err());
+
+        let mut kvs = KvStore::repair(&tmpdir, options.clone()).unwrap();
+        assert_eq!(kvs.keys().len(), 49);
+        assert_eq!(kvs.latest_seq, 52);
+        assert_eq!(kvs.get_bytes(b"key0").unwrap(), None);
+        assert_eq!(kvs.get_bytes(b"key1").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(kvs.get_bytes(b"key2").unwrap(), Some(vec![b'v'; 64]));
+        kvs.close().unwrap();
+        assert_eq!(std::fs::read(tmpdir.path().join(CMD_CORRUPT_FILE)).unwrap(), vec![0, 0, 0, 2, 0xff, 0xff]);
+        assert!(KvStore::verify(&tmpdir, &options).unwrap().is_ok());
+
+        // a tail dropped before is kept.
+        (&cmd).write_all(&[0, 0, 0, 1, 0xfe]).unwrap();
+        KvStore::repair(&tmpdir, options.clone()).unwrap().close().unwrap();
+        assert_eq!(std::fs::read(tmpdir.path().join(CMD_CORRUPT_FILE)).unwrap(), vec![0, 0, 0, 2, 0xff, 0xff]);
+        let second = format!("{}.1", CMD_CORRUPT_FILE);
+        assert_eq!(std::fs::read(tmpdir.path().join(second)).unwrap(), vec![0, 0, 0, 1, 0xfe]);
+
+        // a tail too long to be a torn write.
+        (&cmd).write_all(&[0, 0, 0, 1, 0xfe]).unwrap();
+        (&cmd).write_all(&vec![0; MAX_DROPPED_BYTES as usize]).unwrap();
+        assert!(matches!(KvStore::repair(&tmpdir, options), Err(KvsError::UnsafeRepair(_))));
+    }
+
+    #[test]
+    fn test_repair_with_wrong_key() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options {
+            encryption_key: Some(crate::EncryptionKey::new([7u8; 32])),
+            ..Options::default()
+        };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set_bytes(b"key1", b"value1").unwrap();
+        kvs.close().unwrap();
+        let cmd_path = tmpdir.path().join("cmd.wal");
+        let data = std::fs::read(&cmd_path).unwrap();
+
+        assert!(matches!(KvStore::repair(&tmpdir, Options::default()), Err(KvsError::UnsafeRepair(_))));
+        assert_eq!(std::fs::read(&cmd_path).unwrap(), data);
+        assert!(!tmpdir.path().join(CMD_CORRUPT_FILE).exists());
+    }
+
+    #[test]
+    fn test_verify_partial_written_cmd() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        kvs.set_bytes(b"key1", b"value1").unwrap();
+        kvs.close().unwrap();
+        let cmd = OpenOptions::new().append(true).open(tmpdir.path().join("cmd.wal")).unwrap();
+        (&cmd).write_all(&[0, 0, 0, 9, 1]).unwrap();
+
+        let report = KvStore::verify(&tmpdir, &Options::default()).unwrap();
+        assert_eq!(report.cmd_records, 1);
+        assert_eq!(report.problems.len(), 1, "{:?}", report.problems);
+        assert!(report.problems[0].contains("5 bytes of partial written record"));
+    }
+
+    #[test]
+    fn test_verify_flipped_bit() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        kvs.set_bytes(b"key1", b"value1").unwrap();
+        kvs.set_bytes(b"key2", b"value2").unwrap();
+        kvs.close().unwrap();
+
+        // a bit of the last value, which still decodes.
+        let path = tmpdir.path().join("cmd.wal");
+        let mut data = std::fs::read(&path).unwrap();
+        let at = data.windows(6).rposition(|w| w == b"value2").unwrap();
+        data[at] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+
+        let report = KvStore::verify(&tmpdir, &Options::default()).unwrap();
+        assert_eq!(report.cmd_records, 1);
+        assert!(report.problems[0].contains("ChecksumMismatch"), "{:?}", report.problems);
+    }
+}
@@ -1,41 +1,93 @@
 use std::io::{SeekFrom, Seek, Read, Write};
+use std::convert::TryFrom;
 use std::fs::File;
 use std::marker::PhantomData;
 //use std::ops::Range;
 
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
-use crate::error::Result;
+use crate::crypto::Cipher;
+use crate::error::{KvsError, Result};
+
+// bytes of crc32 at the end of a plain record.
+const CHECKSUM_LEN: usize = 4;
+
+/// a type of records kept in one kind of log.
+pub trait WalEntry: Serialize+DeserializeOwned {
+    /// id of the log. it's authenticated with the offset of each
+    /// sealed record, so a record moved to another log or offset
+    /// can't be opened.
+    const LOG_ID: u8;
+}
 
 /// flush in background when buffer nearly full
 /// and continue writing in a another buffer.
 pub struct WalLog<T>
-where T: Serialize+DeserializeOwned {
+where T: WalEntry {
     pub fd: File,
+    cipher: Option<Cipher>,
     _t: PhantomData<T>,
 }
 
 impl<T> WalLog<T>
-where T: Serialize+DeserializeOwned {
+where T: WalEntry {
     pub fn new(fd: File) -> Self {
+        Self::with_cipher(fd, None)
+    }
+
+    /// records are sealed by cipher if it's given.
+    pub fn with_cipher(fd: File, cipher: Option<Cipher>) -> Self {
         Self {
             fd,
+            cipher,
             _t: PhantomData{},
         }
     }
 
     pub fn append(&self, mut writer: impl Seek+Write, cmd: &T) -> Result<u64> {
         let offset = writer.seek(SeekFrom::End(0))?;
-        write_wal_entry(writer, cmd)?;
+        write_wal_entry(writer, cmd, self.sealer(offset).as_ref())?;
         Ok(offset)
     }
 
+    /// append records with a single write, return their offsets.
+    pub fn append_batch(&self, mut writer: impl Seek+Write, cmds: &[T]) -> Result<Vec<u64>> {
+        let start = writer.seek(SeekFrom::End(0))?;
+        let mut buf = Vec::new();
+        let mut offsets = Vec::with_capacity(cmds.len());
+        for cmd in cmds {
+            let offset = start + buf.len() as u64;
+            offsets.push(offset);
+            write_wal_entry(&mut buf, cmd, self.sealer(offset).as_ref())?;
+        }
+        writer.write_all(&buf)?;
+        Ok(offsets)
+    }
+
     pub fn read(&self, mut reader: impl Read+Seek,offset: u64) -> Result<T> {
         reader.seek(SeekFrom::Start(offset))?;
-        Ok(read_wal_entry(reader)?)
+        read_wal_entry(reader, self.sealer(offset).as_ref())
     }
 
-    pub fn iter<S: Read+Seek>(reader: &mut S) -> WalIterator<T, S> {
-        WalIterator::new(reader).unwrap()
+    /// decode a record from a mapped log.
+    pub fn read_slice(&self, data: &[u8], offset: u64) -> Result<T> {
+        if offset >= data.len() as u64 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof, "offset beyond mapped log").into());
+        }
+        read_wal_entry(&data[offset as usize..], self.sealer(offset).as_ref())
+    }
+
+    pub fn iter<'a, S: Read+Seek>(&self, reader: &'a mut S) -> WalIterator<'a, T, S> {
+        self.iter_from(reader, 0)
+    }
+
+    /// iterate records from a given offset.
+    pub fn iter_from<'a, S: Read+Seek>(&self, reader: &'a mut S, offset: u64) -> WalIterator<'a, T, S> {
+        WalIterator::new(reader, self.cipher.clone(), offset).unwrap()
+    }
+
+    fn sealer(&self, offset: u64) -> Option<Sealer<'_>> {
+        self.cipher.as_ref().map(|cipher| Sealer::new(cipher, T::LOG_ID, offset))
     }
 
     // pub fn truncate<Idx>(&mut self, rang: Range<Idx>) -> Result<()> {
@@ -44,53 +96,104 @@ where T: Serialize+DeserializeOwned {
 }
 
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 struct Location {
     fid: u16,
     offset: u32,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Serialize)]
 struct OnDiskIndex {
     key: String,
     loc: Location,
 }
 
-pub struct WalIterator<'a, T, S> where T: DeserializeOwned, S: Read+Seek {
+/// iterate records until the end of log. a partial written
+/// record at the tail is treated as the end, other errors are
+/// kept and reported by `finish`.
+pub struct WalIterator<'a, T, S> where T: WalEntry, S: Read+Seek {
     reader: &'a mut S,
+    cipher: Option<Cipher>,
+    error: Option<KvsError>,
     _t: PhantomData<T>,
 }
 
 impl<'a, T, S> Iterator for WalIterator<'a, T, S>
-where T: DeserializeOwned, S: Read+Seek {
+where T: WalEntry, S: Read+Seek {
     type Item = (u64, T);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self._next().ok()
+        if self.error.is_some() {
+            return None;
+        }
+        match self._next() {
+            Ok(item) => Some(item),
+            Err(KvsError::IoError(ref err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof => None,
+            Err(err) => {
+                self.error = Some(err);
+                None
+            }
+        }
     }
 }
 
 impl<'a, T, S> WalIterator<'a, T, S>
-where T: DeserializeOwned, S:Seek+Read {
-    fn new(reader: &'a mut S) -> Result<Self> {
+where T: WalEntry, S:Seek+Read {
+    fn new(reader: &'a mut S, cipher: Option<Cipher>, offset: u64) -> Result<Self> {
         // let mut reader = BufReader::new(s);
-        reader.seek(SeekFrom::Start(0))?;
+        reader.seek(SeekFrom::Start(offset))?;
         Ok(
-            Self { reader, _t: PhantomData{} }
+            Self { reader, cipher, error: None, _t: PhantomData{} }
         )
     }
-    fn _next(&mut self) -> Result<(<WalIterator<T, S> as Iterator>::Item)> {
-        Ok(
-            (self.reader.seek(SeekFrom::Current(0))?,
-             read_wal_entry(&mut self.reader)?)
-        )
+    fn _next(&mut self) -> Result<<WalIterator<'_, T, S> as Iterator>::Item> {
+        let offset = self.reader.stream_position()?;
+        let sealer = self.cipher.as_ref().map(|cipher| Sealer::new(cipher, T::LOG_ID, offset));
+        Ok((offset, read_wal_entry(&mut self.reader, sealer.as_ref())?))
+    }
+
+    /// report the error which stopped iterating.
+    pub fn finish(self) -> Result<()> {
+        match self.error {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+/// a cipher along with associated data of a record: id of its log
+/// and its offset.
+pub(crate) struct Sealer<'a> {
+    cipher: &'a Cipher,
+    associated: [u8; 9],
+}
+
+impl<'a> Sealer<'a> {
+    fn new(cipher: &'a Cipher, log_id: u8, offset: u64) -> Self {
+        let mut associated = [log_id; 9];
+        associated[1..].copy_from_slice(&offset.to_be_bytes());
+        Self { cipher, associated }
     }
 }
 
-fn write_wal_entry<T>(mut writer: impl Write, data: T) -> Result<()>
+pub(crate) fn write_wal_entry<T>(mut writer: impl Write, data: T, sealer: Option<&Sealer>) -> Result<()>
 where T: Serialize {
-    let data = serde_json::to_vec(&data)?;
-    let data_len = data.len() as u32;
+    let mut data = bincode::serialize(&data)?;
+    match sealer {
+        Some(sealer) => data = sealer.cipher.seal(&data, &sealer.associated)?,
+        // a plain record ends with its checksum, a sealed one is
+        // already checked by its tag.
+        None => {
+            let checksum = crc32fast::hash(&data);
+            data.extend_from_slice(&checksum.to_be_bytes());
+        },
+    }
+    // a longer record can't be framed, rather than being cut.
+    let data_len = u32::try_from(data.len()).map_err(|_| std::io::Error::new(
+        std::io::ErrorKind::InvalidInput, "record is too large for a log"))?;
     let data_len_bytes = data_len.to_be_bytes();
     writer.write_all(&data_len_bytes)?;
     writer.write_all(&data)?;
@@ -98,14 +201,37 @@ where T: Serialize {
     Ok(())
 }
 
-fn read_wal_entry<T>(mut reader: impl Read) -> Result<T>
+pub(crate) fn read_wal_entry<T>(reader: impl Read, sealer: Option<&Sealer>) -> Result<T>
+where T: DeserializeOwned {
+    read_bounded_entry(reader, sealer, u32::MAX)
+}
+
+/// read an entry no longer than `max_len` bytes, which is checked
+/// before its buffer is allocated.
+pub(crate) fn read_bounded_entry<T>(mut reader: impl Read, sealer: Option<&Sealer>, max_len: u32) -> Result<T>
 where T: DeserializeOwned {
     let mut count_bytes = [0u8; 4];
     reader.read_exact(&mut count_bytes)?;
     let data_bytes_count = u32::from_be_bytes(count_bytes);
+    if data_bytes_count > max_len {
+        return Err(KvsError::MessageTooLarge(data_bytes_count));
+    }
     let mut buf = vec![0u8; data_bytes_count as usize];
     reader.read_exact(&mut buf)?;
-    Ok(serde_json::from_slice(&buf)?)
+    match sealer {
+        Some(sealer) => buf = sealer.cipher.open(&buf, &sealer.associated)?,
+        None => {
+            if buf.len() < CHECKSUM_LEN {
+                return Err(KvsError::ChecksumMismatch);
+            }
+            let (data, checksum) = buf.split_at(buf.len() - CHECKSUM_LEN);
+            if crc32fast::hash(data).to_be_bytes() != checksum {
+                return Err(KvsError::ChecksumMismatch);
+            }
+            buf.truncate(buf.len() - CHECKSUM_LEN);
+        },
+    }
+    Ok(bincode::deserialize(&buf)?)
 }
 
 
@@ -126,17 +252,17 @@ mod tests {
         }
         std::mem::drop(kvs);
 
-        let fd = File::open(tmpdir.into_path().join("cmd.wal")).unwrap();
+        let fd = File::open(tmpdir.path().join("cmd.wal")).unwrap();
         let mut reader = BufReader::new(fd);
         let wi: WalIterator<OnDiskCommand, BufReader<File>> = WalIterator::new(
-            &mut reader).unwrap();
+            &mut reader, None, 0).unwrap();
         let kvvec:Vec<_> = wi.collect();
         for (idx, (_, cmd)) in kvvec.iter().enumerate() {
             let key = format!("key{}", idx);
             let value = format!("value{}", idx);
-            assert_eq!(key, cmd.key);
+            assert_eq!(key.as_bytes(), &cmd.key[..]);
             if let crate::OnDiskValue::Content(_sequence, content) = &cmd.value {
-                assert_eq!(value, *content);
+                assert_eq!(value.as_bytes(), &content[..]);
             } else {
                 panic!("assert fail");
             }
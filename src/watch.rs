@@ -0,0 +1,248 @@
+use std::io::BufReader;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+
+use crate::{KvStore, OnDiskCommand, OnDiskValue};
+use crate::error::{KvsError, Result};
+
+// changes queued for a watcher before it's closed.
+const WATCH_QUEUE: usize = 4096;
+// records of log read for one batch of changes.
+const CHANGE_BATCH: usize = 1024;
+
+/// a write of key: its sequence, the key, and the new value or
+/// `None` if it's removed.
+pub type Change = (u64, Vec<u8>, Option<Vec<u8>>);
+
+pub(crate) struct WatchSender {
+    prefix: Vec<u8>,
+    sender: SyncSender<Change>,
+    overflowed: Arc<AtomicBool>,
+}
+
+/// changes of keys with a prefix, written after it's created.
+/// changes are queued until they are taken, drop it to stop watching.
+/// a watcher which falls too far behind is closed, see `overflowed`.
+pub struct Watcher {
+    receiver: Receiver<Change>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Iterator for Watcher {
+    type Item = Change;
+
+    /// wait for next change. `None` once the store is dropped.
+    fn next(&mut self) -> Option<Change> {
+        self.receiver.recv().ok()
+    }
+}
+
+impl Watcher {
+    /// take a queued change without waiting.
+    pub fn try_next(&mut self) -> Option<Change> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// whether the watcher is closed because its queue was full.
+    /// changes after the last one taken are missed.
+    pub fn overflowed(&self) -> bool {
+        self.overflowed.load(Ordering::SeqCst)
+    }
+}
+
+/// position of reading changes from cmd.wal, up to its end at the
+/// time the cursor is made.
+pub struct ChangeCursor {
+    seq: u64,
+    compactions: u64,
+    offset: u64,
+    end: u64,
+}
+
+impl KvStore {
+    /// watch writes of keys starting with a given prefix.
+    /// an empty prefix watches all keys.
+    pub fn watch(&mut self, prefix: &[u8]) -> Watcher {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_QUEUE);
+        let overflowed = Arc::new(AtomicBool::new(false));
+        self.watchers.push(WatchSender { prefix: prefix.to_vec(), sender, overflowed: overflowed.clone() });
+        Watcher { receiver, overflowed }
+    }
+
+    /// a cursor to read changes after a given sequence which are in
+    /// cmd.wal now.
+    pub fn change_cursor(&self, seq: u64) -> Result<ChangeCursor> {
+        Ok(ChangeCursor {
+            seq,
+            compactions: self.compactions,
+            offset: 0,
+            end: self.wal_cmd.fd.metadata()?.len(),
+        })
+    }
+
+    /// next changes at a cursor, `None` once it's at the end. each call
+    /// reads a bounded part of log, so the store can be unlocked
+    /// between them. changes are in order of sequence within a call,
+    /// live records rewritten by compaction come first. removals and
+    /// overwritten values before the last compaction are no longer in
+    /// log, and a compaction after the cursor is made fails it.
+    pub fn changes_since(&self, cursor: &mut ChangeCursor) -> Result<Option<Vec<Change>>> {
+        if cursor.compactions != self.compactions {
+            return Err(KvsError::Compacted);
+        }
+        if cursor.offset >= cursor.end {
+            return Ok(None);
+        }
+        let mut reader = BufReader::new(&self.wal_cmd.fd);
+        let mut iter = self.wal_cmd.iter_from(&mut reader, cursor.offset);
+        let mut changes = Vec::new();
+        let mut next = cursor.end;
+        for (read, (offset, OnDiskCommand{key, value})) in (&mut iter).enumerate() {
+            if read == CHANGE_BATCH || offset >= cursor.end {
+                next = std::cmp::min(offset, cursor.end);
+                break;
+            }
+            if value.sequence() <= cursor.seq {
+                continue;
+            }
+            match value.into_plain()? {
+                OnDiskValue::Content(sequence, content) => changes.push((sequence, key, Some(content))),
+                OnDiskValue::DeletedKey(sequence) => changes.push((sequence, key, None)),
+                _ => {},
+            }
+        }
+        iter.finish()?;
+        cursor.offset = next;
+        // compaction rewrites live records out of order.
+        changes.sort_by_key(|change| change.0);
+        Ok(Some(changes))
+    }
+
+    /// changes of plain commands to be written, only if they are watched.
+    pub(crate) fn changes_to_notify(&self, cmds: &[OnDiskCommand]) -> Vec<Change> {
+        if self.watchers.is_empty() {
+            return Vec::new();
+        }
+        cmds.iter().filter_map(|OnDiskCommand{key, value}| match value {
+            OnDiskValue::Content(sequence, content) => Some((*sequence, key.clone(), Some(content.clone()))),
+            OnDiskValue::DeletedKey(sequence) => Some((*sequence, key.clone(), None)),
+            _ => None,
+        }).collect()
+    }
+
+    /// send written changes to watchers. dropped watchers and the ones
+    /// whose queue is full are removed, writes never wait for them.
+    pub(crate) fn notify(&mut self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        self.watchers.retain(|watcher| {
+            changes.iter()
+                .filter(|change| change.1.starts_with(&watcher.prefix))
+                .all(|change| match watcher.sender.try_send(change.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        watcher.overflowed.store(true, Ordering::SeqCst);
+                        false
+                    },
+                    Err(TrySendError::Disconnected(_)) => false,
+                })
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Options, WriteBatch};
+
+    #[test]
+    fn test_watch_prefix() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        kvs.set("user:1".into(), "before".into()).unwrap();
+        let mut users = kvs.watch(b"user:");
+        let mut all = kvs.watch(b"");
+
+        kvs.set("user:1".into(), "alice".into()).unwrap();
+        kvs.set("order:1".into(), "book".into()).unwrap();
+        kvs.write_batch(WriteBatch::new().remove(b"user:1").set(b"user:2", b"bob").clone()).unwrap();
+        assert!(kvs.remove("user:3".into()).is_err());
+
+        assert_eq!(users.try_next(), Some((2, b"user:1".to_vec(), Some(b"alice".to_vec()))));
+        assert_eq!(users.try_next(), Some((4, b"user:1".to_vec(), None)));
+        assert_eq!(users.try_next(), Some((5, b"user:2".to_vec(), Some(b"bob".to_vec()))));
+        assert_eq!(users.try_next(), None);
+        assert_eq!(all.try_next().map(|change| change.0), Some(2));
+        assert_eq!(all.try_next().map(|change| change.0), Some(3));
+
+        std::mem::drop(users);
+        kvs.set("user:4".into(), "carol".into()).unwrap();
+        assert_eq!(kvs.watchers.len(), 1);
+        std::mem::drop(kvs);
+        assert_eq!(all.by_ref().map(|change| change.0).collect::<Vec<_>>(), vec![4, 5, 6]);
+        assert!(!all.overflowed());
+    }
+
+    #[test]
+    fn test_watcher_overflow() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut kvs = KvStore::open(&tmpdir, Options::default()).unwrap();
+        let mut watcher = kvs.watch(b"");
+        let mut batch = WriteBatch::new();
+        for i in 0..=WATCH_QUEUE {
+            batch.set(format!("key{}", i).as_bytes(), b"value");
+        }
+        kvs.write_batch(batch).unwrap();
+        assert!(kvs.watchers.is_empty());
+        assert_eq!(watcher.by_ref().count(), WATCH_QUEUE);
+        assert!(watcher.overflowed());
+    }
+
+    fn all_changes(kvs: &KvStore, seq: u64) -> Result<Vec<Change>> {
+        let mut cursor = kvs.change_cursor(seq)?;
+        let mut changes = Vec::new();
+        while let Some(batch) = kvs.changes_since(&mut cursor)? {
+            changes.extend(batch);
+        }
+        Ok(changes)
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let options = Options { compress_threshold: Some(32), ..Options::default() };
+        let mut kvs = KvStore::open(&tmpdir, options).unwrap();
+        kvs.set("key1".into(), "value1".into()).unwrap();
+        kvs.set("key2".into(), "x".repeat(100)).unwrap();
+        kvs.remove("key1".into()).unwrap();
+        kvs.set("key3".into(), "value3".into()).unwrap();
+
+        let changes = all_changes(&kvs, 1).unwrap();
+        assert_eq!(changes, vec![
+            (2, b"key2".to_vec(), Some(b"x".repeat(100))),
+            (3, b"key1".to_vec(), None),
+            (4, b"key3".to_vec(), Some(b"value3".to_vec())),
+        ]);
+
+        // the removal is dropped by compaction.
+        kvs.compact().unwrap();
+        let seqs: Vec<_> = all_changes(&kvs, 0).unwrap().into_iter().map(|change| change.0).collect();
+        assert_eq!(seqs, vec![2, 4]);
+
+        // a cursor reads log in batches, up to its end when it's made.
+        for i in 0..CHANGE_BATCH {
+            kvs.set(format!("key{}", i), "value".into()).unwrap();
+        }
+        let mut cursor = kvs.change_cursor(0).unwrap();
+        kvs.set("key".into(), "value".into()).unwrap();
+        assert_eq!(kvs.changes_since(&mut cursor).unwrap().map(|changes| changes.len()), Some(CHANGE_BATCH));
+        assert_eq!(kvs.changes_since(&mut cursor).unwrap().map(|changes| changes.len()), Some(2));
+        assert!(kvs.changes_since(&mut cursor).unwrap().is_none());
+
+        let mut cursor = kvs.change_cursor(0).unwrap();
+        kvs.compact().unwrap();
+        assert!(matches!(kvs.changes_since(&mut cursor), Err(KvsError::Compacted)));
+    }
+}